@@ -1,4 +1,4 @@
-pub fn _start() {
+pub fn _start() -> i64 {
     let x = 1;
     let y = 0;
 
@@ -8,6 +8,15 @@ pub struct Unit {
 #[derive(Debug, Clone)]
 pub enum UnitItem {
     FDecl(FDecl),
+    /// Tokens skipped after a syntax error
+    Error(Span),
+}
+
+#[derive(Debug, Clone)]
+pub enum ReplLine {
+    FDecl(FDecl),
+    Statement(Statement),
+    Expr(Expr),
 }
 
 #[derive(Debug, Clone)]
@@ -18,30 +27,39 @@ pub struct Id {
 
 #[derive(Debug, Clone)]
 pub struct FDecl {
+    pub loc: Span,
     pub name: Id,
     pub params: Vec<Param>,
+    /// `None` if the function doesn't return a value
+    pub ret: Option<TypeRef>,
     pub body: Block,
     pub public: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct Param {
+    pub loc: Span,
     pub name: Id,
     pub typ: TypeRef,
 }
 
 #[derive(Debug, Clone)]
 pub struct Block {
+    pub loc: Span,
     pub items: Vec<Statement>,
+    /// An expression ending the block without a `;`, as its value
+    pub tail: Option<Box<Expr>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Loop {
+    pub loc: Span,
     pub body: Block,
 }
 
 #[derive(Debug, Clone)]
 pub struct If {
+    pub loc: Span,
     pub cond: Expr,
     pub body: Block,
 }
@@ -52,21 +70,26 @@ pub enum Statement {
     Loop(Loop),
     If(If),
 
-    Continue,
-    Break,
+    Continue(Span),
+    Break(Span),
 
     VDecl(VDecl),
     Return(Return),
     Expr(Expr),
+
+    /// Tokens skipped after a syntax error
+    Error(Span),
 }
 
 #[derive(Debug, Clone)]
 pub struct Return {
+    pub loc: Span,
     pub expr: Option<Expr>,
 }
 
 #[derive(Debug, Clone)]
 pub struct VDecl {
+    pub loc: Span,
     pub name: Id,
     pub typ: Option<TypeRef>,
     pub value: Option<Expr>,
@@ -77,6 +100,7 @@ pub enum Expr {
     Call(Call),
     Block(Block),
     Bexp(Bexp),
+    Uexp(Uexp),
     Identifier(Id),
     IntLit(IntLit),
     FloatLit(FloatLit),
@@ -101,6 +125,7 @@ impl Unit {
         for item in items.drain(..) {
             match item {
                 UnitItem::FDecl(fun) => file.funs.push(fun),
+                UnitItem::Error(_) => {}
             }
         }
 
@@ -108,11 +133,40 @@ impl Unit {
     }
 }
 
+impl Statement {
+    pub fn loc(&self) -> &Span {
+        match self {
+            Self::Block(b) => &b.loc,
+            Self::Loop(l) => &l.loc,
+            Self::If(i) => &i.loc,
+            Self::Continue(loc) | Self::Break(loc) => loc,
+            Self::VDecl(vd) => &vd.loc,
+            Self::Return(ret) => &ret.loc,
+            Self::Expr(ex) => ex.loc(),
+            Self::Error(loc) => loc,
+        }
+    }
+}
+
+impl Expr {
+    pub fn loc(&self) -> &Span {
+        match self {
+            Self::Call(c) => &c.loc,
+            Self::Block(b) => &b.loc,
+            Self::Bexp(b) => &b.loc,
+            Self::Uexp(u) => &u.loc,
+            Self::Identifier(id) => &id.loc,
+            Self::IntLit(il) => &il.loc,
+            Self::FloatLit(fl) => &fl.loc,
+        }
+    }
+}
+
 impl Id {
-    pub fn new(loc: Span) -> Self {
+    pub fn new(loc: Span, value: &str) -> Self {
         Self {
-            loc: loc.clone(),
-            value: loc.slice().into(),
+            loc,
+            value: value.into(),
         }
     }
 }
@@ -124,12 +178,14 @@ pub struct TypeRef {
 
 #[derive(Debug, Clone)]
 pub struct Call {
+    pub loc: Span,
     pub target: Box<Expr>,
     pub args: Vec<Expr>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Bexp {
+    pub loc: Span,
     pub operator: Bop,
     pub lhs: Box<Expr>,
     pub rhs: Box<Expr>,
@@ -142,6 +198,8 @@ pub enum Bop {
     Mul,
     Div,
 
+    Eq,
+    Ne,
     Gt,
     GtEq,
     Lt,
@@ -153,6 +211,7 @@ pub enum Bop {
 impl Bop {
     pub fn as_expr(self, lhs: Box<Expr>, rhs: Box<Expr>) -> Bexp {
         Bexp {
+            loc: lhs.loc().to(rhs.loc()),
             lhs,
             operator: self,
             rhs,
@@ -160,6 +219,21 @@ impl Bop {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Uexp {
+    pub loc: Span,
+    pub operator: Uop,
+    pub operand: Box<Expr>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Uop {
+    /// `-x`
+    Neg,
+    /// `!x`, 1 if `x` is 0 and 0 otherwise
+    Not,
+}
+
 #[derive(Debug, Clone)]
 pub enum AssOp {
     Plus,
@@ -190,6 +264,7 @@ impl BopEx {
         match self {
             Self::Base(operator) => operator.as_expr(lhs, rhs),
             Self::Ass(operator) => Bexp {
+                loc: lhs.loc().to(rhs.loc()),
                 lhs: lhs.clone(),
                 operator: Bop::Assign,
                 rhs: Box::new(Expr::Bexp(operator.as_operator().as_expr(lhs, rhs))),
@@ -0,0 +1,290 @@
+use crate::{
+    ir, jit, lexer, link, middle, object, parser,
+    target::{self, ObjectFormat, Target},
+};
+use parser::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A stage of the pipeline whose output can be written out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Emit {
+    Tokens,
+    Ast,
+    Ir,
+    Asm,
+    Obj,
+    Exe,
+}
+
+impl Emit {
+    pub const NAMES: &'static [&'static str] = &["tokens", "ast", "ir", "asm", "obj", "exe"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "tokens" => Self::Tokens,
+            "ast" => Self::Ast,
+            "ir" => Self::Ir,
+            "asm" => Self::Asm,
+            "obj" => Self::Obj,
+            "exe" => Self::Exe,
+            _ => return None,
+        })
+    }
+
+    fn extension(self, target: &dyn Target) -> &'static str {
+        match self {
+            Self::Tokens => "tokens",
+            Self::Ast => "ast",
+            Self::Ir => "ir",
+            Self::Asm => "asm",
+            Self::Obj => target.object_format().extension(),
+            Self::Exe => target.exe_extension(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linker {
+    /// `link::link_elf`, no external tools needed
+    Builtin,
+    /// Whatever `Target::link_command` runs
+    System,
+}
+
+impl Linker {
+    pub const NAMES: &'static [&'static str] = &["builtin", "system"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "builtin" => Self::Builtin,
+            "system" => Self::System,
+            _ => return None,
+        })
+    }
+}
+
+/// How `run` executes code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// Native code, mapped into our own process
+    Jit,
+    /// `ir::interp`, slower but works anywhere
+    Interp,
+}
+
+impl Engine {
+    pub const NAMES: &'static [&'static str] = &["jit", "interp"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "jit" => Self::Jit,
+            "interp" => Self::Interp,
+            _ => return None,
+        })
+    }
+}
+
+pub struct Options<'a> {
+    pub input: PathBuf,
+    /// Explicit output path, see `Options::output_path`
+    pub output: Option<PathBuf>,
+    pub emit: Vec<Emit>,
+    pub target: &'a dyn Target,
+    /// Defaults to the built-in linker where it supports the target
+    pub linker: Option<Linker>,
+}
+
+impl<'a> Options<'a> {
+    fn wants(&self, e: Emit) -> bool {
+        self.emit.contains(&e)
+    }
+
+    fn linker(&self) -> Linker {
+        match (self.linker, self.target.object_format()) {
+            (Some(linker), _) => linker,
+            (None, ObjectFormat::Elf64) => Linker::Builtin,
+            (None, _) => Linker::System,
+        }
+    }
+
+    /// With a single `--emit`, `-o` is the exact path of that output.
+    /// Everything else is named after `-o` (or the input file) with the
+    /// extension swapped, like rustc does.
+    fn output_path(&self, e: Emit) -> PathBuf {
+        match &self.output {
+            Some(output) if self.emit == [e] => output.clone(),
+            Some(output) => output.with_extension(e.extension(self.target)),
+            None => self.input.with_extension(e.extension(self.target)),
+        }
+    }
+
+    /// Refuses to go ahead if any output would land on the input file -
+    /// which is where an input without an extension puts an executable
+    /// for targets whose executables have none
+    fn check_outputs(&self) -> Result<()> {
+        let mut written = self.emit.clone();
+        if self.wants(Emit::Exe) && self.linker() == Linker::System {
+            written.push(Emit::Obj);
+        }
+        for e in written {
+            let path = self.output_path(e);
+            if same_file(&path, &self.input) {
+                return Err(io::Error::other(format!(
+                    "output {} would overwrite the input file, use -o to pick another path",
+                    path.display()
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+pub fn build(opts: &Options) -> Result<()> {
+    opts.check_outputs()?;
+    let mut sources = parser::SourceMap::default();
+    let file = sources.load_path(&opts.input)?;
+    let source = sources.get(file);
+    if opts.wants(Emit::Tokens) {
+        let mut dump = String::new();
+        for token in lexer::lex(source)? {
+            dump.push_str(&token.describe(source));
+            dump.push('\n');
+        }
+        write_output(opts, Emit::Tokens, dump)?;
+    }
+
+    let unit = parser::parse(source)?;
+    if opts.wants(Emit::Ast) {
+        write_output(opts, Emit::Ast, format!("{:#?}\n", unit))?;
+    }
+
+    let funcs = middle::transform(&unit, &sources, opts.target)?;
+    if opts.wants(Emit::Ir) {
+        write_output(opts, Emit::Ir, format!("{:#?}\n", funcs))?;
+    }
+
+    let v: Vec<_> = funcs.iter().collect();
+    if opts.wants(Emit::Asm) {
+        write_asm(opts, &v[..])?;
+    }
+
+    if opts.wants(Emit::Exe) && opts.linker() == Linker::Builtin {
+        link_builtin(opts, &v[..])?;
+    } else if opts.wants(Emit::Obj) || opts.wants(Emit::Exe) {
+        let obj_path = write_object(opts, &v[..])?;
+
+        if opts.wants(Emit::Exe) {
+            let exe_path = opts.output_path(Emit::Exe);
+            run_command(opts.target.link_command(&obj_path, &exe_path))?;
+        }
+        discard_intermediate(opts, Emit::Obj, &obj_path)?;
+    }
+
+    Ok(())
+}
+
+/// Compiles `input` in memory and calls its entry point
+pub fn run(input: &Path, engine: Engine) -> Result<i64> {
+    let mut sources = parser::SourceMap::default();
+    let file = sources.load_path(input)?;
+    let unit = parser::parse(sources.get(file))?;
+    let target = target::host();
+    let funcs = middle::transform(&unit, &sources, target)?;
+
+    let v: Vec<_> = funcs.iter().collect();
+    let result = match engine {
+        Engine::Jit => jit::Jit::new(&v[..])?.call_entry()?,
+        Engine::Interp => {
+            ir::interp::Interp::new(&v[..], target).call(target.entry_symbol(), &[])?
+        }
+    };
+    Ok(result)
+}
+
+fn write_asm(opts: &Options, funcs: &[&ir::Func]) -> Result<PathBuf> {
+    let mut buf: Vec<u8> = Vec::new();
+    ir::emit::emit_all(&mut buf, funcs, opts.target)?;
+    write_output(opts, Emit::Asm, buf)
+}
+
+fn write_object(opts: &Options, funcs: &[&ir::Func]) -> Result<PathBuf> {
+    let code = ir::encode::encode_all(funcs, opts.target)?;
+    let obj = match opts.target.object_format() {
+        ObjectFormat::Elf64 => object::elf::write_relocatable(&code),
+        ObjectFormat::Win64 => object::coff::write_object(&code),
+    };
+    write_output(opts, Emit::Obj, obj)
+}
+
+/// Builds the executable in-process, writing an object file on the side
+/// only if it was asked for
+fn link_builtin(opts: &Options, funcs: &[&ir::Func]) -> Result<()> {
+    if let ObjectFormat::Win64 = opts.target.object_format() {
+        return Err(io::Error::other(format!(
+            "the built-in linker cannot link for {}, use --linker=system",
+            opts.target.name()
+        ))
+        .into());
+    }
+
+    let code = ir::encode::encode_all(funcs, opts.target)?;
+    if opts.wants(Emit::Obj) {
+        write_output(opts, Emit::Obj, object::elf::write_relocatable(&code))?;
+    }
+
+    let exe = link::link_elf(&code, opts.target.entry_symbol())?;
+    let exe_path = write_output(opts, Emit::Exe, exe)?;
+    set_executable(&exe_path)
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut perms = std::fs::metadata(path)?.permissions();
+    perms.set_mode(perms.mode() | 0o111);
+    std::fs::set_permissions(path, perms)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn write_output<C: AsRef<[u8]>>(opts: &Options, e: Emit, contents: C) -> Result<PathBuf> {
+    let path = opts.output_path(e);
+    std::fs::write(&path, contents)?;
+    Ok(path)
+}
+
+/// Removes a file we only needed to produce a later stage
+fn discard_intermediate(opts: &Options, e: Emit, path: &Path) -> Result<()> {
+    if !opts.wants(e) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn run_command(mut cmd: Command) -> Result<()> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let status = cmd
+        .status()
+        .map_err(|e| io::Error::new(e.kind(), format!("could not run {}: {}", program, e)))?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{} failed with {}", program, status)).into());
+    }
+    Ok(())
+}
@@ -1,5 +1,6 @@
 use crate::*;
 use std::io::{self, Write};
+use target::Target;
 
 static CODE_INDENT: &'static str = "            ";
 
@@ -8,14 +9,16 @@ type Result = std::result::Result<(), io::Error>;
 struct Stack<'a> {
     w: &'a mut dyn io::Write,
     f: &'a Func,
+    target: &'a dyn Target,
     blocks: Vec<BlockRef>,
 }
 
 impl<'a> Stack<'a> {
-    pub fn new(w: &'a mut dyn io::Write, f: &'a Func) -> Self {
+    pub fn new(w: &'a mut dyn io::Write, f: &'a Func, target: &'a dyn Target) -> Self {
         Self {
             w,
             f,
+            target,
             blocks: Vec::new(),
         }
     }
@@ -33,30 +36,6 @@ impl<'a> Stack<'a> {
         self.blocks.pop();
         r
     }
-
-    pub fn offset(&self, l: LocalRef) -> i64 {
-        let mut offset = 0i64;
-        for l in &self.f.locals[..l.0] {
-            offset += l.typ.byte_width(self.f);
-        }
-
-        offset
-    }
-
-    pub fn save_rsp(&mut self) -> Result {
-        emit_op(self, &Op::mov(Reg::RBP, Reg::RSP))?;
-        Ok(())
-    }
-
-    pub fn alloc_locals(&mut self) -> Result {
-        emit_op(self, &Op::sub(Reg::RSP, self.f.locals_stack_size()))?;
-        Ok(())
-    }
-
-    pub fn dealloc_locals(&mut self) -> Result {
-        emit_op(self, &Op::add(Reg::RSP, self.f.locals_stack_size()))?;
-        Ok(())
-    }
 }
 
 impl<'a> io::Write for Stack<'a> {
@@ -69,33 +48,45 @@ impl<'a> io::Write for Stack<'a> {
     }
 }
 
-pub fn emit_all(w: &mut dyn io::Write, funcs: &[&Func]) -> Result {
+pub fn emit_all(w: &mut dyn io::Write, funcs: &[&Func], target: &dyn Target) -> Result {
     for f in funcs {
         if f.public {
-            write!(w, "{}global {}\n", CODE_INDENT, f.name)?;
+            writeln!(w, "{}global {}", CODE_INDENT, symbol(&f.name))?;
         }
     }
 
     write!(w, "{}section .text\n", CODE_INDENT)?;
 
-    for f in funcs {
-        write!(w, "{}:\n", f.name)?;
-        emit_func(w, f)?;
+    let bodies = par::try_map(funcs, |f| -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        writeln!(buf, "{}:", symbol(&f.name))?;
+        emit_func(&mut buf, f, target)?;
+        Ok(buf)
+    })?;
+    for body in bodies {
+        w.write_all(&body)?;
     }
 
     Ok(())
 }
 
-fn emit_func(w: &mut dyn io::Write, f: &Func) -> Result {
+/// A symbol as NASM should read it. The `$` makes sure it's taken as a
+/// name even if it's a register, an instruction or a keyword, like a
+/// function called `rax`, `ret` or `section` would be.
+fn symbol(name: &str) -> String {
+    format!("${}", name)
+}
+
+fn emit_func(w: &mut dyn io::Write, f: &Func, target: &dyn Target) -> Result {
     let entry = f.entry;
-    let mut st = Stack::new(w, f);
+    let mut st = Stack::new(w, f, target);
 
-    st.save_rsp()?;
-    st.alloc_locals()?;
+    for op in &frame::prologue(f, target)? {
+        emit_op(&mut st, op)?;
+    }
 
     st.push(entry, |st| -> Result {
         emit_block(st, entry)?;
-        emit_op(st, &Op::Ret(None))?;
         Ok(())
     })?;
 
@@ -173,6 +164,29 @@ fn emit_op(st: &mut Stack, op: &Op) -> Result {
                 Ok(())
             })?;
         }
+        Op::Imul(ref o) => {
+            instruction(st, "imul", |st| {
+                emit_location(st, &o.lhs)?;
+                write!(st, ", ")?;
+                emit_location(st, &o.rhs)?;
+                Ok(())
+            })?;
+        }
+        Op::Neg(ref o) => {
+            instruction(st, "neg", |st| {
+                emit_opsize(st, o)?;
+                emit_location(st, o)?;
+                Ok(())
+            })?;
+        }
+        Op::Cqo => instruction(st, "cqo", |_| Ok(()))?,
+        Op::Idiv(ref o) => {
+            instruction(st, "idiv", |st| {
+                emit_opsize(st, o)?;
+                emit_location(st, o)?;
+                Ok(())
+            })?;
+        }
         Op::Cmp(ref o) => {
             instruction(st, "cmp", |st| {
                 emit_opsize(st, &o.lhs)?;
@@ -182,8 +196,8 @@ fn emit_op(st: &mut Stack, op: &Op) -> Result {
                 Ok(())
             })?;
         }
-        Op::Jg(ref o) => {
-            instruction(st, "jg", |st| {
+        Op::Jcc(ref o) => {
+            instruction(st, jcc_name(o.cond), |st| {
                 let l = o.dst.borrow(st.f);
                 write!(st, "{}", l.name)?;
                 Ok(())
@@ -196,18 +210,43 @@ fn emit_op(st: &mut Stack, op: &Op) -> Result {
                 Ok(())
             })?;
         }
-        Op::Ret(ref o) => {
-            if let Some(o) = o {
-                emit_op(st, &Op::mov(Reg::RAX, *o))?;
+        Op::Call(ref o) => {
+            let ops = frame::call(st.f, st.target, o)?;
+            for op in &ops.before {
+                emit_op(st, op)?;
             }
-
-            st.dealloc_locals()?;
-
-            instruction(st, "ret", |st| {
-                write!(st, "0")?;
+            instruction(st, "call", |st| {
+                write!(st, "{}", symbol(&o.target))?;
+                Ok(())
+            })?;
+            for op in &ops.after {
+                emit_op(st, op)?;
+            }
+        }
+        Op::Push(ref o) => {
+            instruction(st, "push", |st| {
+                emit_location(st, o)?;
+                Ok(())
+            })?;
+        }
+        Op::Pop(ref o) => {
+            instruction(st, "pop", |st| {
+                emit_location(st, o)?;
                 Ok(())
             })?;
         }
+        Op::Ret => {
+            let epilogue = frame::epilogue(st.f, st.target);
+            for op in &epilogue.ops {
+                emit_op(st, op)?;
+            }
+
+            let name = match epilogue.leave {
+                frame::Leave::Ret => "ret",
+                frame::Leave::Syscall => "syscall",
+            };
+            instruction(st, name, |_| Ok(()))?;
+        }
         Op::Comment(ref c) => {
             comment(st, c.as_ref().map(|s| &s[..]).unwrap_or(""))?;
         }
@@ -216,6 +255,21 @@ fn emit_op(st: &mut Stack, op: &Op) -> Result {
     Ok(())
 }
 
+fn jcc_name(cond: Cond) -> &'static str {
+    match cond {
+        Cond::E => "je",
+        Cond::Ne => "jne",
+        Cond::L => "jl",
+        Cond::Le => "jle",
+        Cond::G => "jg",
+        Cond::Ge => "jge",
+        Cond::B => "jb",
+        Cond::Be => "jbe",
+        Cond::A => "ja",
+        Cond::Ae => "jae",
+    }
+}
+
 fn emit_opsize(st: &mut Stack, loc: &Location) -> Result {
     if loc.is_displaced() {
         let op_size = byte_width_to_opsize(loc.byte_width(st.f));
@@ -228,7 +282,7 @@ fn emit_location(st: &mut Stack, loc: &Location) -> Result {
     match loc {
         Location::Register(r) => r.write_nasm_name(st)?,
         Location::Local(l) => {
-            emit_location(st, &Reg::RBP.displaced(-st.offset(*l)))?;
+            emit_location(st, &Reg::RBP.displaced(-frame::local_offset(st.f, *l)))?;
         }
         Location::Displaced(d) => {
             write!(st, "[")?;
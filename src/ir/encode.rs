@@ -0,0 +1,781 @@
+use super::*;
+use crate::{par, target::Target};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+
+type Result<T> = std::result::Result<T, io::Error>;
+
+/// Machine code for a set of functions, laid out back to back
+#[derive(Debug, Default)]
+pub struct Code {
+    pub text: Vec<u8>,
+    pub symbols: Vec<Symbol>,
+    pub relocs: Vec<Reloc>,
+}
+
+/// Where a function ended up in `Code::text`
+#[derive(Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub public: bool,
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// A 32-bit pc-relative reference to a symbol, left for the linker
+/// to fill in
+#[derive(Debug)]
+pub struct Reloc {
+    /// Where the displacement goes in `Code::text`
+    pub offset: usize,
+    pub symbol: String,
+    /// Added to the symbol's address - the displacement is relative
+    /// to the end of the instruction, not the start of the field
+    pub addend: i64,
+}
+
+/// A chunk of a function's code. Jumps are kept apart until the
+/// function is laid out, because their size depends on how far
+/// away their target ends up.
+enum Piece {
+    Bytes(Vec<u8>),
+    Label(LabelRef),
+    Jump(Jump),
+    Call(String),
+}
+
+/// `call rel32`: opcode, then the displacement
+const CALL_SIZE: usize = 5;
+
+struct Jump {
+    /// Condition code as found in `Jcc` opcodes, `None` for `jmp`
+    cond: Option<u8>,
+    dst: LabelRef,
+}
+
+/// Condition code as found in `Jcc` opcodes
+fn cond_code(cond: Cond) -> u8 {
+    match cond {
+        Cond::B => 0x2,
+        Cond::Ae => 0x3,
+        Cond::E => 0x4,
+        Cond::Ne => 0x5,
+        Cond::Be => 0x6,
+        Cond::A => 0x7,
+        Cond::L => 0xC,
+        Cond::Ge => 0xD,
+        Cond::Le => 0xE,
+        Cond::G => 0xF,
+    }
+}
+
+impl Jump {
+    fn size(&self, near: bool) -> usize {
+        match (self.cond, near) {
+            (_, false) => 2,
+            (None, true) => 5,
+            (Some(_), true) => 6,
+        }
+    }
+
+    fn encode(&self, near: bool, disp: i64) -> Vec<u8> {
+        let mut out = match (self.cond, near) {
+            (None, false) => vec![0xEB],
+            (Some(cc), false) => vec![0x70 | cc],
+            (None, true) => vec![0xE9],
+            (Some(cc), true) => vec![0x0F, 0x80 | cc],
+        };
+        if near {
+            out.extend(&(disp as i32).to_le_bytes());
+        } else {
+            out.push(disp as i8 as u8);
+        }
+        out
+    }
+}
+
+struct Stack<'a> {
+    f: &'a Func,
+    target: &'a dyn Target,
+    blocks: Vec<BlockRef>,
+    pieces: Vec<Piece>,
+}
+
+impl<'a> Stack<'a> {
+    pub fn new(f: &'a Func, target: &'a dyn Target) -> Self {
+        Self {
+            f,
+            target,
+            blocks: Vec::new(),
+            pieces: Vec::new(),
+        }
+    }
+
+    pub fn push<F, R>(&mut self, b: BlockRef, f: F) -> R
+    where
+        F: Fn(&mut Self) -> R,
+    {
+        self.blocks.push(b);
+        let r = f(self);
+        self.blocks.pop();
+        r
+    }
+
+    fn bytes(&mut self, bytes: Vec<u8>) {
+        self.pieces.push(Piece::Bytes(bytes))
+    }
+}
+
+pub fn encode_all(funcs: &[&Func], target: &dyn Target) -> Result<Code> {
+    let mut code = Code::default();
+
+    let encoded = par::try_map(funcs, |f| encode_func(f, target))?;
+    for (f, (text, relocs)) in funcs.iter().zip(encoded) {
+        let offset = code.text.len();
+        code.text.extend(text);
+        code.relocs.extend(relocs.into_iter().map(|r| Reloc {
+            offset: offset + r.offset,
+            ..r
+        }));
+        code.symbols.push(Symbol {
+            name: f.name.clone(),
+            public: f.public,
+            offset,
+            size: code.text.len() - offset,
+        });
+    }
+
+    Ok(code)
+}
+
+/// Returns the function's code, and relocations relative to its start
+fn encode_func(f: &Func, target: &dyn Target) -> Result<(Vec<u8>, Vec<Reloc>)> {
+    let entry = f.entry;
+    let mut st = Stack::new(f, target);
+
+    for op in &frame::prologue(f, target)? {
+        encode_op(&mut st, op)?;
+    }
+
+    st.push(entry, |st| -> Result<()> {
+        encode_block(st, entry)?;
+        Ok(())
+    })?;
+
+    assemble(f, &st.pieces)
+}
+
+/// Picks the shortest encoding for every jump and lays out the pieces.
+///
+/// Jumps start out short and are only ever grown, which can only push
+/// other targets further away, so this settles after a few passes.
+fn assemble(f: &Func, pieces: &[Piece]) -> Result<(Vec<u8>, Vec<Reloc>)> {
+    let mut near = vec![false; pieces.len()];
+
+    let (offsets, labels) = loop {
+        let mut offsets = Vec::with_capacity(pieces.len());
+        let mut labels = HashMap::new();
+        let mut offset = 0;
+        for (i, piece) in pieces.iter().enumerate() {
+            offsets.push(offset);
+            offset += match piece {
+                Piece::Bytes(b) => b.len(),
+                Piece::Label(l) => {
+                    labels.insert(*l, offset);
+                    0
+                }
+                Piece::Jump(j) => j.size(near[i]),
+                Piece::Call(_) => CALL_SIZE,
+            };
+        }
+
+        let mut changed = false;
+        for (i, piece) in pieces.iter().enumerate() {
+            if let Piece::Jump(j) = piece {
+                let disp = jump_disp(f, &labels, j, offsets[i] + j.size(near[i]))?;
+                if !near[i] && i8::try_from(disp).is_err() {
+                    near[i] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        if !changed {
+            break (offsets, labels);
+        }
+    };
+
+    let mut out = Vec::new();
+    let mut relocs = Vec::new();
+    for (i, piece) in pieces.iter().enumerate() {
+        match piece {
+            Piece::Bytes(b) => out.extend(b),
+            Piece::Label(_) => {}
+            Piece::Jump(j) => {
+                let disp = jump_disp(f, &labels, j, offsets[i] + j.size(near[i]))?;
+                out.extend(j.encode(near[i], disp));
+            }
+            Piece::Call(symbol) => {
+                out.push(0xE8);
+                relocs.push(Reloc {
+                    offset: out.len(),
+                    symbol: symbol.clone(),
+                    addend: -4,
+                });
+                out.extend(&[0; 4]);
+            }
+        }
+    }
+    Ok((out, relocs))
+}
+
+/// Displacement from the end of a jump to its target
+fn jump_disp(f: &Func, labels: &HashMap<LabelRef, usize>, j: &Jump, end: usize) -> Result<i64> {
+    match labels.get(&j.dst) {
+        Some(&dst) => Ok(dst as i64 - end as i64),
+        None => Err(io::Error::other(format!(
+            "{}: jump to {}, which is never placed",
+            f.name,
+            j.dst.borrow(f).name
+        ))),
+    }
+}
+
+fn encode_block(st: &mut Stack, block: BlockRef) -> Result<()> {
+    let block = block.borrow(st.f);
+
+    for op in &block.ops {
+        encode_op(st, op)?;
+    }
+
+    Ok(())
+}
+
+fn encode_op(st: &mut Stack, op: &Op) -> Result<()> {
+    match op {
+        Op::Block(ref b) => st.push(*b, |st| -> Result<()> {
+            encode_block(st, *b)?;
+            Ok(())
+        })?,
+        Op::Label(ref l) => st.pieces.push(Piece::Label(*l)),
+        Op::Xor(ref o) => {
+            let bytes = alu(st, &XOR, &o.lhs, &o.rhs)?;
+            st.bytes(bytes);
+        }
+        Op::Add(ref o) => {
+            let bytes = alu(st, &ADD, &o.lhs, &o.rhs)?;
+            st.bytes(bytes);
+        }
+        Op::Sub(ref o) => {
+            let bytes = alu(st, &SUB, &o.lhs, &o.rhs)?;
+            st.bytes(bytes);
+        }
+        Op::Cmp(ref o) => {
+            let bytes = alu(st, &CMP, &o.lhs, &o.rhs)?;
+            st.bytes(bytes);
+        }
+        Op::Imul(ref o) => {
+            let bytes = imul(st, &o.lhs, &o.rhs)?;
+            st.bytes(bytes);
+        }
+        Op::Neg(ref o) => {
+            let bytes = unary(st, 3, o)?;
+            st.bytes(bytes);
+        }
+        Op::Cqo => st.bytes(vec![0x48, 0x99]),
+        Op::Idiv(ref o) => {
+            let bytes = unary(st, 7, o)?;
+            st.bytes(bytes);
+        }
+        Op::Mov(ref o) => {
+            let bytes = mov(st, &o.dst, &o.src)?;
+            st.bytes(bytes);
+        }
+        Op::Jcc(ref o) => st.pieces.push(Piece::Jump(Jump {
+            cond: Some(cond_code(o.cond)),
+            dst: o.dst,
+        })),
+        Op::Jmp(ref o) => st.pieces.push(Piece::Jump(Jump {
+            cond: None,
+            dst: o.dst,
+        })),
+        Op::Call(ref o) => {
+            let ops = frame::call(st.f, st.target, o)?;
+            for op in &ops.before {
+                encode_op(st, op)?;
+            }
+            st.pieces.push(Piece::Call(o.target.clone()));
+            for op in &ops.after {
+                encode_op(st, op)?;
+            }
+        }
+        Op::Push(ref o) => {
+            let bytes = push_pop(st, 0x50, o)?;
+            st.bytes(bytes);
+        }
+        Op::Pop(ref o) => {
+            let bytes = push_pop(st, 0x58, o)?;
+            st.bytes(bytes);
+        }
+        Op::Ret => {
+            let epilogue = frame::epilogue(st.f, st.target);
+            for op in &epilogue.ops {
+                encode_op(st, op)?;
+            }
+
+            match epilogue.leave {
+                frame::Leave::Ret => st.bytes(vec![0xC3]),
+                frame::Leave::Syscall => st.bytes(vec![0x0F, 0x05]),
+            }
+        }
+        Op::Comment(_) => {}
+    }
+
+    Ok(())
+}
+
+/// An operand as seen by the ModRM byte
+enum Rm {
+    Reg(u8),
+    Mem { base: u8, disp: i32 },
+}
+
+impl Rm {
+    fn from_location(st: &Stack, loc: &Location) -> Result<Option<Self>> {
+        Ok(match loc {
+            Location::Register(r) => Some(Rm::Reg(r.number())),
+            Location::Local(l) => {
+                Self::from_location(st, &Reg::RBP.displaced(-frame::local_offset(st.f, *l)))?
+            }
+            Location::Displaced(d) => match i32::try_from(d.displacement) {
+                Ok(disp) => Some(Rm::Mem {
+                    base: d.register.number(),
+                    disp,
+                }),
+                Err(_) => return Err(unencodable(st, "displacement", loc)),
+            },
+            Location::Imm64(_) => None,
+        })
+    }
+
+    fn base(&self) -> u8 {
+        match self {
+            Rm::Reg(r) => *r,
+            Rm::Mem { base, .. } => *base,
+        }
+    }
+
+    /// REX prefix with the W bit set, for 64-bit operand size
+    fn rex_w(&self, reg: u8) -> u8 {
+        0x48 | ((reg >> 3) << 2) | (self.base() >> 3)
+    }
+
+    /// ModRM byte, plus SIB and displacement bytes if needed
+    fn modrm(&self, reg: u8) -> Vec<u8> {
+        let reg = (reg & 7) << 3;
+        match *self {
+            Rm::Reg(r) => vec![0b11_000_000 | reg | (r & 7)],
+            Rm::Mem { base, disp } => {
+                let rm = base & 7;
+                // rbp and r13 have no displacement-less form: that
+                // encoding means rip-relative
+                let (mode, disp_bytes) = if disp == 0 && rm != 0b101 {
+                    (0b00, vec![])
+                } else if let Ok(disp) = i8::try_from(disp) {
+                    (0b01, vec![disp as u8])
+                } else {
+                    (0b10, disp.to_le_bytes().to_vec())
+                };
+
+                let mut out = vec![(mode << 6) | reg | rm];
+                // rsp and r12 as a base need a SIB byte
+                if rm == 0b100 {
+                    out.push(0x24);
+                }
+                out.extend(disp_bytes);
+                out
+            }
+        }
+    }
+
+    fn encode(&self, opcode: &[u8], reg: u8) -> Vec<u8> {
+        let mut out = vec![self.rex_w(reg)];
+        out.extend(opcode);
+        out.extend(self.modrm(reg));
+        out
+    }
+}
+
+/// Encodings of a two-operand arithmetic instruction
+struct Alu {
+    /// `op r/m64, r64`
+    mr: u8,
+    /// `op r64, r/m64`
+    rm: u8,
+    /// ModRM reg field for `op r/m64, imm`
+    digit: u8,
+}
+
+const ADD: Alu = Alu {
+    mr: 0x01,
+    rm: 0x03,
+    digit: 0,
+};
+const SUB: Alu = Alu {
+    mr: 0x29,
+    rm: 0x2B,
+    digit: 5,
+};
+const XOR: Alu = Alu {
+    mr: 0x31,
+    rm: 0x33,
+    digit: 6,
+};
+const CMP: Alu = Alu {
+    mr: 0x39,
+    rm: 0x3B,
+    digit: 7,
+};
+
+fn alu(st: &Stack, alu: &Alu, lhs: &Location, rhs: &Location) -> Result<Vec<u8>> {
+    let dst = match Rm::from_location(st, lhs)? {
+        Some(dst) => dst,
+        None => return Err(unencodable(st, "destination", lhs)),
+    };
+
+    if let Location::Imm64(v) = *rhs {
+        if let Ok(v) = i8::try_from(v) {
+            let mut out = dst.encode(&[0x83], alu.digit);
+            out.push(v as u8);
+            return Ok(out);
+        }
+
+        let v = match i32::try_from(v) {
+            Ok(v) => v,
+            Err(_) => return Err(unencodable(st, "immediate", rhs)),
+        };
+        let mut out = match dst {
+            // rax has a shorter form without a ModRM byte
+            Rm::Reg(0) => vec![0x48, (alu.digit << 3) | 0x05],
+            _ => dst.encode(&[0x81], alu.digit),
+        };
+        out.extend(&v.to_le_bytes());
+        return Ok(out);
+    }
+
+    match (dst, Rm::from_location(st, rhs)?) {
+        (dst, Some(Rm::Reg(src))) => Ok(dst.encode(&[alu.mr], src)),
+        (Rm::Reg(dst), Some(src)) => Ok(src.encode(&[alu.rm], dst)),
+        _ => Err(unencodable(st, "operand pair", rhs)),
+    }
+}
+
+fn mov(st: &Stack, dst: &Location, src: &Location) -> Result<Vec<u8>> {
+    let dst_rm = match Rm::from_location(st, dst)? {
+        Some(dst) => dst,
+        None => return Err(unencodable(st, "destination", dst)),
+    };
+
+    if let Location::Imm64(v) = *src {
+        return match dst_rm {
+            // a 32-bit mov zero-extends, so it's enough for most values
+            Rm::Reg(r) if u32::try_from(v).is_ok() => {
+                let mut out = if r >= 8 { vec![0x41] } else { vec![] };
+                out.push(0xB8 | (r & 7));
+                out.extend(&(v as u32).to_le_bytes());
+                Ok(out)
+            }
+            _ if i32::try_from(v).is_ok() => {
+                let mut out = dst_rm.encode(&[0xC7], 0);
+                out.extend(&(v as i32).to_le_bytes());
+                Ok(out)
+            }
+            Rm::Reg(r) => {
+                let mut out = vec![0x48 | (r >> 3), 0xB8 | (r & 7)];
+                out.extend(&v.to_le_bytes());
+                Ok(out)
+            }
+            Rm::Mem { .. } => Err(unencodable(st, "immediate", src)),
+        };
+    }
+
+    match (dst_rm, Rm::from_location(st, src)?) {
+        (dst, Some(Rm::Reg(src))) => Ok(dst.encode(&[0x89], src)),
+        (Rm::Reg(dst), Some(src)) => Ok(src.encode(&[0x8B], dst)),
+        _ => Err(unencodable(st, "operand pair", src)),
+    }
+}
+
+/// `imul r64, r/m64`, or `imul r64, r/m64, imm` with the destination
+/// as both operands
+fn imul(st: &Stack, lhs: &Location, rhs: &Location) -> Result<Vec<u8>> {
+    let dst = match lhs {
+        Location::Register(r) => r.number(),
+        _ => return Err(unencodable(st, "destination", lhs)),
+    };
+
+    if let Location::Imm64(v) = *rhs {
+        let rm = Rm::Reg(dst);
+        if let Ok(v) = i8::try_from(v) {
+            let mut out = rm.encode(&[0x6B], dst);
+            out.push(v as u8);
+            return Ok(out);
+        }
+        return match i32::try_from(v) {
+            Ok(v) => {
+                let mut out = rm.encode(&[0x69], dst);
+                out.extend(&v.to_le_bytes());
+                Ok(out)
+            }
+            Err(_) => Err(unencodable(st, "immediate", rhs)),
+        };
+    }
+
+    match Rm::from_location(st, rhs)? {
+        Some(src) => Ok(src.encode(&[0x0F, 0xAF], dst)),
+        None => Err(unencodable(st, "operand", rhs)),
+    }
+}
+
+/// One-operand instructions of the `F7` group, like `neg` and `idiv`,
+/// told apart by the ModRM reg field
+fn unary(st: &Stack, digit: u8, loc: &Location) -> Result<Vec<u8>> {
+    match Rm::from_location(st, loc)? {
+        Some(rm) => Ok(rm.encode(&[0xF7], digit)),
+        None => Err(unencodable(st, "operand", loc)),
+    }
+}
+
+/// `push r64` and `pop r64` share a layout: the register goes in the
+/// low bits of the opcode
+fn push_pop(st: &Stack, opcode: u8, loc: &Location) -> Result<Vec<u8>> {
+    match loc {
+        Location::Register(r) => {
+            let r = r.number();
+            let mut out = if r >= 8 { vec![0x41] } else { vec![] };
+            out.push(opcode | (r & 7));
+            Ok(out)
+        }
+        _ => Err(unencodable(st, "operand", loc)),
+    }
+}
+
+fn unencodable(st: &Stack, what: &str, loc: &Location) -> io::Error {
+    io::Error::other(format!("{}: cannot encode {} {:?}", st.f.name, what, loc))
+}
+
+impl Reg {
+    /// Register number as used in ModRM, SIB and REX
+    pub fn number(self) -> u8 {
+        match self {
+            Self::RAX => 0,
+            Self::RCX => 1,
+            Self::RDX => 2,
+            Self::RBX => 3,
+            Self::RSP => 4,
+            Self::RBP => 5,
+            Self::RSI => 6,
+            Self::RDI => 7,
+            Self::R8 => 8,
+            Self::R9 => 9,
+            Self::R10 => 10,
+            Self::R11 => 11,
+            Self::R12 => 12,
+            Self::R13 => 13,
+            Self::R14 => 14,
+            Self::R15 => 15,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::target::{LinuxX64, WindowsX64};
+
+    /// Encodes `ops` on their own, without a prologue or epilogue
+    fn encode_ops(f: &Func, ops: &[Op]) -> (Vec<u8>, Vec<Reloc>) {
+        let mut st = Stack::new(f, &LinuxX64);
+        for op in ops {
+            encode_op(&mut st, op).unwrap();
+        }
+        assemble(f, &st.pieces).unwrap()
+    }
+
+    fn bytes(ops: &[Op]) -> Vec<u8> {
+        encode_ops(&Func::new("f"), ops).0
+    }
+
+    #[test]
+    fn rex_prefixes() {
+        assert_eq!(bytes(&[Op::mov(Reg::RAX, Reg::RBX)]), [0x48, 0x89, 0xD8]);
+        // REX.R for the source, REX.B for the destination
+        assert_eq!(bytes(&[Op::mov(Reg::RAX, Reg::R9)]), [0x4C, 0x89, 0xC8]);
+        assert_eq!(bytes(&[Op::mov(Reg::R12, Reg::RAX)]), [0x49, 0x89, 0xC4]);
+        assert_eq!(bytes(&[Op::mov(Reg::R12, Reg::R9)]), [0x4D, 0x89, 0xCC]);
+        assert_eq!(bytes(&[Op::push(Reg::RBP)]), [0x55]);
+        assert_eq!(bytes(&[Op::push(Reg::R12)]), [0x41, 0x54]);
+        assert_eq!(bytes(&[Op::pop(Reg::R15)]), [0x41, 0x5F]);
+    }
+
+    #[test]
+    fn modrm_register_operands() {
+        assert_eq!(bytes(&[Op::add(Reg::RCX, Reg::RDX)]), [0x48, 0x01, 0xD1]);
+        assert_eq!(bytes(&[Op::sub(Reg::RSI, Reg::RDI)]), [0x48, 0x29, 0xFE]);
+        assert_eq!(bytes(&[Op::xor(Reg::RDI, Reg::RDI)]), [0x48, 0x31, 0xFF]);
+        assert_eq!(bytes(&[Op::cmp(Reg::RAX, Reg::R8)]), [0x4C, 0x39, 0xC0]);
+        assert_eq!(
+            bytes(&[Op::imul(Reg::RAX, Reg::RCX)]),
+            [0x48, 0x0F, 0xAF, 0xC1]
+        );
+        assert_eq!(bytes(&[Op::neg(Reg::RAX)]), [0x48, 0xF7, 0xD8]);
+        assert_eq!(bytes(&[Op::cqo()]), [0x48, 0x99]);
+        assert_eq!(bytes(&[Op::idiv(Reg::RCX)]), [0x48, 0xF7, 0xF9]);
+    }
+
+    #[test]
+    fn sib_for_rsp_and_r12_bases() {
+        assert_eq!(
+            bytes(&[Op::mov(Reg::RSP.displaced(0), Reg::RAX)]),
+            [0x48, 0x89, 0x04, 0x24]
+        );
+        assert_eq!(
+            bytes(&[Op::add(Reg::RAX, Reg::RSP.displaced(8))]),
+            [0x48, 0x03, 0x44, 0x24, 0x08]
+        );
+        assert_eq!(
+            bytes(&[Op::mov(Reg::R12.displaced(0), Reg::RAX)]),
+            [0x49, 0x89, 0x04, 0x24]
+        );
+    }
+
+    #[test]
+    fn displacements_for_rbp_and_r13_bases() {
+        // no displacement-less form, that would be rip-relative
+        assert_eq!(
+            bytes(&[Op::mov(Reg::RBP.displaced(0), Reg::RAX)]),
+            [0x48, 0x89, 0x45, 0x00]
+        );
+        assert_eq!(
+            bytes(&[Op::mov(Reg::R13.displaced(0), Reg::RAX)]),
+            [0x49, 0x89, 0x45, 0x00]
+        );
+        assert_eq!(
+            bytes(&[Op::mov(Reg::RAX, Reg::RBP.displaced(-8))]),
+            [0x48, 0x8B, 0x45, 0xF8]
+        );
+        assert_eq!(
+            bytes(&[Op::mov(Reg::RAX, Reg::RBP.displaced(-0x100))]),
+            [0x48, 0x8B, 0x85, 0x00, 0xFF, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn locals_live_below_rbp() {
+        let mut f = Func::new("f");
+        let a = f.push_local("a", Type::I64);
+        let b = f.push_local("b", Type::I64);
+        let (text, _) = encode_ops(&f, &[Op::mov(a, Reg::RAX), Op::mov(Reg::RCX, b)]);
+        assert_eq!(text, [0x48, 0x89, 0x45, 0xF8, 0x48, 0x8B, 0x4D, 0xF0]);
+    }
+
+    #[test]
+    fn immediates() {
+        assert_eq!(bytes(&[Op::add(Reg::RAX, 1)]), [0x48, 0x83, 0xC0, 0x01]);
+        // rax has its own short form
+        assert_eq!(
+            bytes(&[Op::add(Reg::RAX, 0x1000)]),
+            [0x48, 0x05, 0x00, 0x10, 0x00, 0x00]
+        );
+        assert_eq!(
+            bytes(&[Op::sub(Reg::RCX, 0x1000)]),
+            [0x48, 0x81, 0xE9, 0x00, 0x10, 0x00, 0x00]
+        );
+        assert_eq!(bytes(&[Op::mov(Reg::RAX, 60)]), [0xB8, 0x3C, 0, 0, 0]);
+        assert_eq!(bytes(&[Op::mov(Reg::R9, 1)]), [0x41, 0xB9, 1, 0, 0, 0]);
+        assert_eq!(
+            bytes(&[Op::mov(Reg::RAX, -1)]),
+            [0x48, 0xC7, 0xC0, 0xFF, 0xFF, 0xFF, 0xFF]
+        );
+        assert_eq!(
+            bytes(&[Op::mov(Reg::RAX, 1 << 32)]),
+            [0x48, 0xB8, 0, 0, 0, 0, 1, 0, 0, 0]
+        );
+        assert_eq!(bytes(&[Op::imul(Reg::RAX, 10)]), [0x48, 0x6B, 0xC0, 0x0A]);
+    }
+
+    #[test]
+    fn short_jumps() {
+        let mut f = Func::new("f");
+        let l = f.entry.new_label(&mut f);
+        let (text, _) = encode_ops(&f, &[Op::label(l), Op::jmp(l)]);
+        assert_eq!(text, [0xEB, 0xFE]);
+        let (text, _) = encode_ops(&f, &[Op::jcc(Cond::E, l), Op::label(l)]);
+        assert_eq!(text, [0x74, 0x00]);
+        let (text, _) = encode_ops(&f, &[Op::jcc(Cond::G, l), Op::cqo(), Op::label(l)]);
+        assert_eq!(text, [0x7F, 0x02, 0x48, 0x99]);
+    }
+
+    #[test]
+    fn near_jumps() {
+        let mut f = Func::new("f");
+        let l = f.entry.new_label(&mut f);
+        let mut ops = vec![Op::jmp(l), Op::jcc(Cond::L, l)];
+        // 128 bytes is one too far for a short jump's displacement
+        ops.extend((0..64).map(|_| Op::cqo()));
+        ops.push(Op::label(l));
+
+        let (text, _) = encode_ops(&f, &ops);
+        assert_eq!(text[..5], [0xE9, 0x86, 0x00, 0x00, 0x00]);
+        assert_eq!(text[5..11], [0x0F, 0x8C, 0x80, 0x00, 0x00, 0x00]);
+        assert_eq!(text.len(), 11 + 128);
+    }
+
+    #[test]
+    fn calls_leave_a_relocation() {
+        let f = Func::new("f");
+        let (text, relocs) = encode_ops(&f, &[Op::cqo(), Op::call("g", vec![], None)]);
+        assert_eq!(text, [0x48, 0x99, 0xE8, 0, 0, 0, 0]);
+        assert_eq!(relocs.len(), 1);
+        assert_eq!(relocs[0].offset, 3);
+        assert_eq!(relocs[0].symbol, "g");
+        // the displacement counts from the end of the 4-byte field
+        assert_eq!(relocs[0].addend, -4);
+    }
+
+    #[test]
+    fn relocations_are_relative_to_the_text_section() {
+        let mut first = Func::new("first");
+        first.entry.push_op(&mut first, Op::ret());
+        let mut second = Func::new("second");
+        second
+            .entry
+            .push_op(&mut second, Op::call("first", vec![], None));
+        second.entry.push_op(&mut second, Op::ret());
+
+        let code = encode_all(&[&first, &second], &LinuxX64).unwrap();
+        // push rbp; mov rbp, rsp; sub rsp, 0; mov rsp, rbp; pop rbp; ret
+        let frame = [0x55, 0x48, 0x89, 0xE5, 0x48, 0x83, 0xEC, 0x00];
+        let leave = [0x48, 0x89, 0xEC, 0x5D, 0xC3];
+        assert_eq!(code.text[..8], frame);
+        assert_eq!(code.text[8..13], leave);
+        assert_eq!(code.symbols[1].offset, 13);
+        assert_eq!(code.text[13 + 8], 0xE8);
+        assert_eq!(code.relocs[0].offset, 13 + 9);
+        assert_eq!(code.relocs[0].symbol, "first");
+        assert_eq!(code.relocs[0].addend, -4);
+    }
+
+    #[test]
+    fn win64_calls_reserve_shadow_space() {
+        let f = Func::new("f");
+        let mut st = Stack::new(&f, &WindowsX64);
+        encode_op(&mut st, &Op::call("g", vec![Location::Imm64(7)], None)).unwrap();
+        let (text, relocs) = assemble(&f, &st.pieces).unwrap();
+        let mut expected = vec![0x48, 0x83, 0xEC, 0x20, 0xB9, 7, 0, 0, 0, 0xE8, 0, 0, 0, 0];
+        expected.extend(&[0x48, 0x83, 0xC4, 0x20]);
+        assert_eq!(text, expected);
+        assert_eq!(relocs[0].offset, 10);
+    }
+}
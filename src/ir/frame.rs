@@ -0,0 +1,121 @@
+use super::*;
+use crate::target::{EntryExit, Target};
+use std::io;
+
+/// The instruction an epilogue ends with
+#[derive(Debug, Clone, Copy)]
+pub enum Leave {
+    Ret,
+    Syscall,
+}
+
+/// Ops that return from a function, shared by every backend
+pub struct Epilogue {
+    pub ops: Vec<Op>,
+    pub leave: Leave,
+}
+
+/// Ops around a `call` instruction
+pub struct CallOps {
+    pub before: Vec<Op>,
+    pub after: Vec<Op>,
+}
+
+/// Ops that pass `call`'s arguments in registers, and store its result
+pub fn call(f: &Func, target: &dyn Target, call: &Call) -> io::Result<CallOps> {
+    let cc = target.calling_convention();
+    let regs = cc.arg_regs();
+    if call.args.len() > regs.len() {
+        return Err(io::Error::other(format!(
+            "{}: call to {} with {} arguments, but {} only passes {} in registers",
+            f.name,
+            call.target,
+            call.args.len(),
+            target.name(),
+            regs.len()
+        )));
+    }
+
+    let mut ops = CallOps {
+        before: Vec::new(),
+        after: Vec::new(),
+    };
+    let shadow_space = cc.shadow_space();
+    if shadow_space > 0 {
+        ops.before.push(Op::sub(Reg::RSP, shadow_space));
+    }
+    for (arg, reg) in call.args.iter().zip(regs) {
+        ops.before.push(Op::mov(*reg, *arg));
+    }
+
+    if shadow_space > 0 {
+        ops.after.push(Op::add(Reg::RSP, shadow_space));
+    }
+    if let Some(dst) = call.dst {
+        ops.after.push(Op::mov(dst, cc.return_reg()));
+    }
+    Ok(ops)
+}
+
+/// Offset of a local below `rbp`, which points at the caller's saved
+/// `rbp` - so the first local starts right below it
+pub fn local_offset(f: &Func, l: LocalRef) -> i64 {
+    let mut offset = 0i64;
+    for l in &f.locals[..=l.0] {
+        offset += l.typ.byte_width(f);
+    }
+
+    offset
+}
+
+/// Bytes reserved below `rbp` for locals. Calls require `rsp` to be
+/// 16-byte aligned, and it is right after `push rbp`, so keep it that way.
+pub fn frame_size(f: &Func) -> i64 {
+    (f.locals_stack_size() + 15) / 16 * 16
+}
+
+/// Ops that set up `f`'s stack frame and spill its arguments
+pub fn prologue(f: &Func, target: &dyn Target) -> io::Result<Vec<Op>> {
+    let regs = target.calling_convention().arg_regs();
+    if f.params.len() > regs.len() {
+        return Err(io::Error::other(format!(
+            "{}: {} parameters, but {} only passes {} in registers",
+            f.name,
+            f.params.len(),
+            target.name(),
+            regs.len()
+        )));
+    }
+
+    let mut ops = vec![
+        Op::push(Reg::RBP),
+        Op::mov(Reg::RBP, Reg::RSP),
+        Op::sub(Reg::RSP, frame_size(f)),
+    ];
+    for (param, reg) in f.params.iter().zip(regs) {
+        ops.push(Op::mov(*param, *reg));
+    }
+    Ok(ops)
+}
+
+/// Ops that tear down `f`'s stack frame and hand the return register
+/// back to the caller - or to the OS, if `f` is the process entry point
+pub fn epilogue(f: &Func, target: &dyn Target) -> Epilogue {
+    let ret_reg = target.calling_convention().return_reg();
+    let mut ops = vec![Op::mov(Reg::RSP, Reg::RBP), Op::pop(Reg::RBP)];
+
+    if let (true, EntryExit::Syscall(nr)) = (f.name == target.entry_symbol(), target.entry_exit()) {
+        // the exit status is the first syscall argument
+        ops.push(Op::mov(Reg::RDI, ret_reg));
+        ops.push(Op::mov(Reg::RAX, nr));
+        return Epilogue {
+            ops,
+            leave: Leave::Syscall,
+        };
+    }
+
+    Epilogue {
+        ops,
+        leave: Leave::Ret,
+    }
+}
@@ -0,0 +1,531 @@
+use super::*;
+use crate::target::Target;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+use std::rc::Rc;
+
+type Result<T> = std::result::Result<T, io::Error>;
+
+/// Bytes of emulated stack, shared by all frames
+const STACK_SIZE: usize = 1024 * 1024;
+
+/// Executes `ir::Func`s op by op, on an emulated machine that lays out
+/// frames the same way the native backends do.
+///
+/// Only `Cmp` sets flags: nothing in the IR branches on the result of
+/// arithmetic.
+pub struct Interp<'a> {
+    funcs: HashMap<&'a str, &'a Func>,
+    target: &'a dyn Target,
+    regs: [i64; 16],
+    stack: Vec<u8>,
+    /// Operands of the last `Cmp`
+    flags: Option<(i64, i64)>,
+    /// Flattened functions, built on their first call
+    programs: HashMap<String, Rc<Program<'a>>>,
+}
+
+/// A function flattened into a single op stream, so jumps can target
+/// labels in any block
+struct Program<'a> {
+    ops: Vec<&'a Op>,
+    labels: HashMap<LabelRef, usize>,
+}
+
+impl<'a> Program<'a> {
+    fn new(f: &'a Func) -> Self {
+        let mut p = Self {
+            ops: Vec::new(),
+            labels: HashMap::new(),
+        };
+        p.push_block(f, f.entry);
+        p
+    }
+
+    fn push_block(&mut self, f: &'a Func, block: BlockRef) {
+        for op in &block.borrow(f).ops {
+            match op {
+                Op::Block(b) => self.push_block(f, *b),
+                Op::Label(l) => {
+                    self.labels.insert(*l, self.ops.len());
+                }
+                op => self.ops.push(op),
+            }
+        }
+    }
+}
+
+/// A function call in progress
+struct Frame<'a> {
+    f: &'a Func,
+    program: Rc<Program<'a>>,
+    /// Index of the op being executed in `program`
+    pc: usize,
+    /// Ops to run once the function being called returns
+    after_call: Vec<Op>,
+}
+
+/// What executing one op does to control flow
+enum Flow {
+    Next,
+    Jump(LabelRef),
+    Return(i64),
+}
+
+impl<'a> Interp<'a> {
+    pub fn new(funcs: &[&'a Func], target: &'a dyn Target) -> Self {
+        let mut regs = [0; 16];
+        regs[Reg::RSP.number() as usize] = STACK_SIZE as i64;
+
+        Self {
+            funcs: funcs.iter().map(|f| (f.name.as_str(), *f)).collect(),
+            target,
+            regs,
+            stack: vec![0; STACK_SIZE],
+            flags: None,
+            programs: HashMap::new(),
+        }
+    }
+
+    /// Calls the function named `name` with integer arguments, and
+    /// returns what it returns - or its exit status, for an entry point
+    /// that leaves through a syscall
+    pub fn call(&mut self, name: &str, args: &[i64]) -> Result<i64> {
+        let regs = self.target.calling_convention().arg_regs();
+        if args.len() > regs.len() {
+            return Err(io::Error::other(format!(
+                "{}: {} arguments, but only {} fit in registers",
+                name,
+                args.len(),
+                regs.len()
+            )));
+        }
+        for (reg, arg) in regs.iter().zip(args) {
+            self.set_reg(*reg, *arg);
+        }
+
+        self.invoke(name)
+    }
+
+    /// Runs `name` to completion. Calls push onto an explicit stack of
+    /// frames rather than recursing, so however deep the program
+    /// recurses, it runs out of emulated stack - an error - before we
+    /// run out of our own.
+    fn invoke(&mut self, name: &str) -> Result<i64> {
+        let ret_reg = self.target.calling_convention().return_reg();
+        let mut frames = vec![self.enter(name)?];
+
+        loop {
+            let frame = frames
+                .last_mut()
+                .expect("returning from the last frame ends the loop");
+            let f = frame.f;
+            let op = match frame.program.ops.get(frame.pc) {
+                Some(op) => *op,
+                // native code would run into whatever comes next
+                None => {
+                    return Err(io::Error::other(format!(
+                        "{}: falls off the end without a ret",
+                        f.name
+                    )))
+                }
+            };
+
+            if let Op::Call(o) = op {
+                let ops = frame::call(f, self.target, o)?;
+                for op in &ops.before {
+                    self.exec(f, op)?;
+                }
+                frame.after_call = ops.after;
+                frames.push(self.enter(&o.target)?);
+                continue;
+            }
+
+            match self.exec(f, op)? {
+                Flow::Next => frame.pc += 1,
+                Flow::Jump(l) => match frame.program.labels.get(&l) {
+                    Some(&target) => frame.pc = target,
+                    None => {
+                        return Err(io::Error::other(format!(
+                            "{}: jump to {}, which is never placed",
+                            f.name,
+                            l.borrow(f).name
+                        )))
+                    }
+                },
+                Flow::Return(v) => {
+                    frames.pop();
+                    let caller = match frames.last_mut() {
+                        Some(caller) => caller,
+                        None => return Ok(v),
+                    };
+                    self.set_reg(ret_reg, v);
+                    for op in &std::mem::take(&mut caller.after_call) {
+                        self.exec(caller.f, op)?;
+                    }
+                    caller.pc += 1;
+                }
+            }
+        }
+    }
+
+    /// Sets up a frame for the function named `name`, as if it had just
+    /// been called
+    fn enter(&mut self, name: &str) -> Result<Frame<'a>> {
+        let f = match self.funcs.get(name) {
+            Some(f) => *f,
+            None => return Err(io::Error::other(format!("no function named `{}`", name))),
+        };
+
+        // stands in for the return address `call` would push
+        self.push(0)?;
+
+        for op in &frame::prologue(f, self.target)? {
+            self.exec(f, op)?;
+        }
+
+        let program = self
+            .programs
+            .entry(name.to_string())
+            .or_insert_with(|| Rc::new(Program::new(f)));
+        Ok(Frame {
+            f,
+            program: Rc::clone(program),
+            pc: 0,
+            after_call: Vec::new(),
+        })
+    }
+
+    fn exec(&mut self, f: &Func, op: &Op) -> Result<Flow> {
+        match op {
+            Op::Mov(o) => {
+                let v = self.read(f, &o.src)?;
+                self.write(f, &o.dst, v)?;
+            }
+            Op::Add(o) => {
+                let v = self.read(f, &o.lhs)?.wrapping_add(self.read(f, &o.rhs)?);
+                self.write(f, &o.lhs, v)?;
+            }
+            Op::Sub(o) => {
+                let v = self.read(f, &o.lhs)?.wrapping_sub(self.read(f, &o.rhs)?);
+                self.write(f, &o.lhs, v)?;
+            }
+            Op::Xor(o) => {
+                let v = self.read(f, &o.lhs)? ^ self.read(f, &o.rhs)?;
+                self.write(f, &o.lhs, v)?;
+            }
+            Op::Imul(o) => {
+                let v = self.read(f, &o.lhs)?.wrapping_mul(self.read(f, &o.rhs)?);
+                self.write(f, &o.lhs, v)?;
+            }
+            Op::Neg(o) => {
+                let v = self.read(f, o)?.wrapping_neg();
+                self.write(f, o, v)?;
+            }
+            Op::Cqo => {
+                let sign = if self.reg(Reg::RAX) < 0 { -1 } else { 0 };
+                self.set_reg(Reg::RDX, sign);
+            }
+            Op::Idiv(o) => {
+                let dividend =
+                    (i128::from(self.reg(Reg::RDX)) << 64) | i128::from(self.reg(Reg::RAX) as u64);
+                let divisor = i128::from(self.read(f, o)?);
+                if divisor == 0 {
+                    return Err(io::Error::other(format!("{}: division by zero", f.name)));
+                }
+                // out of range quotients fault, like on the real thing
+                let quotient = i64::try_from(dividend / divisor)
+                    .map_err(|_| io::Error::other(format!("{}: division overflow", f.name)))?;
+                self.set_reg(Reg::RAX, quotient);
+                self.set_reg(Reg::RDX, (dividend % divisor) as i64);
+            }
+            Op::Cmp(o) => {
+                self.flags = Some((self.read(f, &o.lhs)?, self.read(f, &o.rhs)?));
+            }
+            Op::Jcc(o) => {
+                let (lhs, rhs) = self.flags(f)?;
+                let (ulhs, urhs) = (lhs as u64, rhs as u64);
+                let holds = match o.cond {
+                    Cond::E => lhs == rhs,
+                    Cond::Ne => lhs != rhs,
+                    Cond::L => lhs < rhs,
+                    Cond::Le => lhs <= rhs,
+                    Cond::G => lhs > rhs,
+                    Cond::Ge => lhs >= rhs,
+                    Cond::B => ulhs < urhs,
+                    Cond::Be => ulhs <= urhs,
+                    Cond::A => ulhs > urhs,
+                    Cond::Ae => ulhs >= urhs,
+                };
+                if holds {
+                    return Ok(Flow::Jump(o.dst));
+                }
+            }
+            Op::Jmp(o) => return Ok(Flow::Jump(o.dst)),
+            Op::Call(_) => unreachable!("`invoke` runs calls"),
+            Op::Push(o) => {
+                let v = self.read(f, o)?;
+                self.push(v)?;
+            }
+            Op::Pop(o) => {
+                let v = self.pop()?;
+                self.write(f, o, v)?;
+            }
+            Op::Block(_) => unreachable!("`Program` flattens blocks"),
+            Op::Label(_) | Op::Comment(_) => {}
+            Op::Ret => {
+                let epilogue = frame::epilogue(f, self.target);
+                for op in &epilogue.ops {
+                    self.exec(f, op)?;
+                }
+
+                return Ok(Flow::Return(match epilogue.leave {
+                    frame::Leave::Ret => {
+                        self.pop()?;
+                        self.reg(self.target.calling_convention().return_reg())
+                    }
+                    // the exit status, as the OS would see it
+                    frame::Leave::Syscall => self.reg(Reg::RDI),
+                }));
+            }
+        }
+
+        Ok(Flow::Next)
+    }
+
+    fn flags(&self, f: &Func) -> Result<(i64, i64)> {
+        self.flags
+            .ok_or_else(|| io::Error::other(format!("{}: conditional jump before any cmp", f.name)))
+    }
+
+    fn reg(&self, r: Reg) -> i64 {
+        self.regs[r.number() as usize]
+    }
+
+    fn set_reg(&mut self, r: Reg, v: i64) {
+        self.regs[r.number() as usize] = v;
+    }
+
+    /// Stack offset a memory operand points to
+    fn address(&self, f: &Func, loc: &Location) -> Result<Option<usize>> {
+        let (base, disp) = match loc {
+            Location::Local(l) => (Reg::RBP, -frame::local_offset(f, *l)),
+            Location::Displaced(d) => (d.register, d.displacement),
+            _ => return Ok(None),
+        };
+
+        let addr = self.reg(base).wrapping_add(disp);
+        if addr < 0 {
+            // frames grow down from the top, so this is a frame too many
+            return Err(io::Error::other(format!("{}: stack overflow", f.name)));
+        }
+        if addr as usize + 8 > self.stack.len() {
+            return Err(io::Error::other(format!(
+                "{}: {:?} points outside the stack",
+                f.name, loc
+            )));
+        }
+        Ok(Some(addr as usize))
+    }
+
+    fn read(&self, f: &Func, loc: &Location) -> Result<i64> {
+        if let Some(addr) = self.address(f, loc)? {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&self.stack[addr..addr + 8]);
+            return Ok(i64::from_le_bytes(bytes));
+        }
+
+        Ok(match loc {
+            Location::Register(r) => self.reg(*r),
+            Location::Imm64(v) => *v,
+            _ => unreachable!("memory operands are handled above"),
+        })
+    }
+
+    fn write(&mut self, f: &Func, loc: &Location, v: i64) -> Result<()> {
+        if let Some(addr) = self.address(f, loc)? {
+            self.stack[addr..addr + 8].copy_from_slice(&v.to_le_bytes());
+            return Ok(());
+        }
+
+        match loc {
+            Location::Register(r) => self.set_reg(*r, v),
+            _ => {
+                return Err(io::Error::other(format!(
+                    "{}: cannot write to {:?}",
+                    f.name, loc
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, v: i64) -> Result<()> {
+        let rsp = self.reg(Reg::RSP) - 8;
+        if rsp < 0 {
+            return Err(io::Error::other("stack overflow"));
+        }
+        self.set_reg(Reg::RSP, rsp);
+        let addr = rsp as usize;
+        self.stack[addr..addr + 8].copy_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<i64> {
+        let addr = self.reg(Reg::RSP) as usize;
+        if addr + 8 > self.stack.len() {
+            return Err(io::Error::other("stack underflow"));
+        }
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.stack[addr..addr + 8]);
+        self.set_reg(Reg::RSP, (addr + 8) as i64);
+        Ok(i64::from_le_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{jit, middle, parser, target};
+
+    fn compile(source: &str) -> Vec<Func> {
+        let mut sources = parser::SourceMap::default();
+        let file = sources.load_string(source).unwrap();
+        let unit = parser::parse(sources.get(file)).unwrap();
+        middle::transform(&unit, &sources, target::host()).unwrap()
+    }
+
+    fn interpret(funcs: &[Func]) -> Result<i64> {
+        let v: Vec<_> = funcs.iter().collect();
+        let target = target::host();
+        Interp::new(&v, target).call(target.entry_symbol(), &[])
+    }
+
+    /// Runs `source` on both engines, which must agree on `expected`.
+    /// Only hosts that can run x86-64 code natively have a JIT.
+    fn agree(source: &str, expected: i64) {
+        let funcs = compile(source);
+        let v: Vec<_> = funcs.iter().collect();
+        assert_eq!(interpret(&funcs).unwrap(), expected, "interp");
+        if cfg!(all(unix, target_arch = "x86_64")) {
+            assert_eq!(
+                jit::Jit::new(&v).unwrap().call_entry().unwrap(),
+                expected,
+                "jit"
+            );
+        }
+    }
+
+    #[test]
+    fn calls() {
+        agree(
+            "fn add(a: i64, b: i64) -> i64 { return a + b; }
+             fn fib(n: i64) -> i64 {
+                 if n < 2 { return n; }
+                 return fib(n - 1) + fib(n - 2);
+             }
+             pub fn _start() -> i64 { return add(fib(15), add(1, 2) * 100); }",
+            910,
+        );
+    }
+
+    #[test]
+    fn tail_expressions() {
+        agree(
+            "fn add(a: i64, b: i64) -> i64 { a + b }
+             fn twice(n: i64) -> i64 {
+                 if n < 0 { return 0; }
+                 add(n, n)
+             }
+             pub fn _start() -> i64 { let x = twice(20); { x; } x + 2 }",
+            42,
+        );
+    }
+
+    #[test]
+    fn loops_with_break_and_continue() {
+        agree(
+            "pub fn _start() -> i64 {
+                 let n = 0;
+                 let sum = 0;
+                 loop {
+                     n += 1;
+                     if n > 20 { break; }
+                     if n / 2 * 2 == n { continue; }
+                     sum += n;
+                     loop { break; }
+                 }
+                 return sum;
+             }",
+            100,
+        );
+    }
+
+    #[test]
+    fn precedence() {
+        agree(
+            "pub fn _start() -> i64 {
+                 let a = 1 + 2 * 3 - 8 / 4 * -(1 + 1);
+                 let b = 7 - 3 - 2;
+                 let c = 100 / 10 / 5;
+                 let d = 1 < 2 == 1;
+                 let e = -7 / 2;
+                 return a * 10000 + b * 1000 + c * 100 + d * 10 + e;
+             }",
+            112_207,
+        );
+    }
+
+    #[test]
+    fn unbounded_recursion_overflows_the_stack() {
+        let funcs = compile(
+            "fn f(n: i64) -> i64 { return f(n + 1); }
+             pub fn _start() -> i64 { return f(1); }",
+        );
+        let err = interpret(&funcs).unwrap_err();
+        assert!(err.to_string().ends_with("stack overflow"), "{}", err);
+    }
+
+    const DIVISION_BY_ZERO: &str = "fn div(a: i64, b: i64) -> i64 { return a / b; }
+         pub fn _start() -> i64 { return div(1, 0); }";
+
+    const DIVISION_OVERFLOW: &str = "fn div(a: i64, b: i64) -> i64 { return a / b; }
+         pub fn _start() -> i64 { return div(-9223372036854775807 - 1, -1); }";
+
+    #[test]
+    fn division_errors() {
+        let err = interpret(&compile(DIVISION_BY_ZERO)).unwrap_err();
+        assert!(err.to_string().ends_with("division by zero"), "{}", err);
+        let err = interpret(&compile(DIVISION_OVERFLOW)).unwrap_err();
+        assert!(err.to_string().ends_with("division overflow"), "{}", err);
+    }
+
+    /// Where the interpreter reports an error, native code faults - in
+    /// a process of its own, so it can't take the tests down with it
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn native_division_faults() {
+        use std::os::unix::{fs::PermissionsExt, process::ExitStatusExt};
+
+        for (name, source) in &[("zero", DIVISION_BY_ZERO), ("overflow", DIVISION_OVERFLOW)] {
+            let funcs = compile(source);
+            let v: Vec<_> = funcs.iter().collect();
+            let target = target::host();
+            let code = encode::encode_all(&v, target).unwrap();
+            let exe = crate::link::link_elf(&code, target.entry_symbol()).unwrap();
+
+            let path = std::env::temp_dir().join(format!(
+                "morning-interp-{}-{}",
+                std::process::id(),
+                name
+            ));
+            std::fs::write(&path, exe).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            let status = std::process::Command::new(&path).status().unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(status.signal(), Some(libc::SIGFPE), "{}: {}", name, status);
+        }
+    }
+}
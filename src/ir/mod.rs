@@ -1,11 +1,11 @@
 #![allow(dead_code)]
 
 pub mod emit;
+pub mod encode;
+pub mod frame;
+pub mod interp;
 
-use once_cell::sync::Lazy;
-use std::sync::Mutex;
-
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct BlockRef(usize);
 
 impl BlockRef {
@@ -18,7 +18,7 @@ impl BlockRef {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct LabelRef(BlockRef, usize);
 
 impl LabelRef {
@@ -49,8 +49,14 @@ pub struct Func {
     pub public: bool,
     pub name: String,
     pub entry: BlockRef,
+    /// Locals that arguments get stored into on entry, in order
+    pub params: Vec<LocalRef>,
+    /// `None` if the function doesn't return a value
+    pub ret: Option<Type>,
     pub locals: Vec<Local>,
     pub blocks: Vec<Block>,
+    /// Labels created so far, see `Func::new_label`
+    label_count: usize,
 }
 
 impl Func {
@@ -60,8 +66,11 @@ impl Func {
             name: name.into(),
             public: false,
             entry: BlockRef(0),
+            params: Vec::new(),
+            ret: None,
             locals: Vec::new(),
             blocks,
+            label_count: 0,
         };
         f.push_block(); // add entry block
         f
@@ -77,8 +86,14 @@ impl Func {
         local
     }
 
+    pub fn push_param<S: Into<String>>(&mut self, name: S, typ: Type) -> LocalRef {
+        let local = self.push_local(name, typ);
+        self.params.push(local);
+        local
+    }
+
     pub fn push_block(&mut self) -> BlockRef {
-        let start_owned = Label::new();
+        let start_owned = self.new_label();
         let block = BlockRef(self.blocks.len());
         let ops = vec![];
 
@@ -91,6 +106,17 @@ impl Func {
         block
     }
 
+    /// Labels are numbered per function, so a function's code never
+    /// depends on what else was compiled. Identifiers can't start with a
+    /// `.`, so labels never clash with a function name.
+    fn new_label(&mut self) -> Label {
+        let label = Label {
+            name: format!(".L{}_{}", self.name, self.label_count),
+        };
+        self.label_count += 1;
+        label
+    }
+
     pub fn locals_stack_size(&self) -> i64 {
         let mut res = 0i64;
         for l in &self.locals {
@@ -108,16 +134,34 @@ pub struct Block {
 }
 
 impl Block {
-    pub fn new_label(&mut self) -> LabelRef {
-        let label_owned = Label::new();
-        let label = LabelRef(self.own_ref, self.labels.len());
-        self.labels.push(label_owned);
-        label
-    }
-
     pub fn push_op<O: Into<Op>>(&mut self, op: O) {
         let op = op.into();
         match op {
+            Op::Mov(ref o)
+                if o.dst.is_displaced() && (o.src.is_displaced() || o.src.is_wide_imm()) =>
+            {
+                self.push_op(Op::mov(Reg::RAX, o.src));
+                self.push_op(Op::mov(o.dst, Reg::RAX));
+                return;
+            }
+            Op::Imul(ref o) if o.lhs.is_displaced() => {
+                // the destination of `imul` has to be a register
+                self.push_op(Op::mov(Reg::RAX, o.lhs));
+                self.push_op(Op::imul(Reg::RAX, o.rhs));
+                self.push_op(Op::mov(o.lhs, Reg::RAX));
+                return;
+            }
+            Op::Cmp(ref o) if o.lhs.is_displaced() && o.rhs.is_displaced() => {
+                self.push_op(Op::mov(Reg::RAX, o.lhs));
+                self.push_op(Op::cmp(Reg::RAX, o.rhs));
+                return;
+            }
+            Op::Sub(ref o) if o.lhs.is_displaced() && o.rhs.is_displaced() => {
+                self.push_op(Op::mov(Reg::RAX, o.lhs));
+                self.push_op(Op::sub(Reg::RAX, o.rhs));
+                self.push_op(Op::mov(o.lhs, Reg::RAX));
+                return;
+            }
             Op::Xor(ref o) => {
                 if o.lhs.is_displaced() && o.rhs.is_displaced() {
                     self.push_op(Op::mov(Reg::RAX, o.lhs));
@@ -143,7 +187,11 @@ impl Block {
 
 impl BlockRef {
     pub fn new_label(self, f: &mut Func) -> LabelRef {
-        self.borrow_mut(f).new_label()
+        let label_owned = f.new_label();
+        let block = self.borrow_mut(f);
+        let label = LabelRef(self, block.labels.len());
+        block.labels.push(label_owned);
+        label
     }
 
     pub fn push_op<O: Into<Op>>(self, f: &mut Func, op: O) {
@@ -156,30 +204,26 @@ pub struct Label {
     pub name: String,
 }
 
-static LABEL_SEED: Lazy<Mutex<i64>> = Lazy::new(|| Mutex::new(0));
-
-impl Label {
-    fn new() -> Self {
-        let mut seed = LABEL_SEED.lock().unwrap();
-        let l = Self {
-            name: format!("label_{}", seed),
-        };
-        *seed += 1;
-        l
-    }
-}
-
 #[derive(Debug)]
 pub struct Local {
     name: String,
     typ: Type,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Type {
     I64,
 }
 
+impl Type {
+    /// The name source code uses for this type
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::I64 => "i64",
+        }
+    }
+}
+
 pub trait Girthy {
     fn byte_width(&self, f: &Func) -> i64;
 }
@@ -198,9 +242,9 @@ pub trait Operand {
 
 macro_rules! impl_operand {
     ($variant: ident($typ: ident)) => {
-        impl Into<Op> for $typ {
-            fn into(self) -> Op {
-                Op::$variant(self)
+        impl From<$typ> for Op {
+            fn from(o: $typ) -> Self {
+                Op::$variant(o)
             }
         }
     };
@@ -216,11 +260,25 @@ pub enum Op {
     Add(Add),
     Cmp(Cmp),
     Sub(Sub),
-    Jg(Jg),
+    Imul(Imul),
+    /// Two's complement negation, in place
+    Neg(Location),
+    /// Sign-extends `rax` into `rdx`, ahead of an `Idiv`
+    Cqo,
+    /// Divides `rdx:rax` by the operand: quotient in `rax`, remainder in
+    /// `rdx`
+    Idiv(Location),
+    /// Jumps if the last `Cmp` meets the condition
+    Jcc(Jcc),
     Jmp(Jmp),
+    Call(Call),
+    Push(Location),
+    Pop(Location),
     Label(LabelRef),
     Block(BlockRef),
-    Ret(Option<Location>),
+    /// Tears down the frame and returns whatever is in the return
+    /// register. Functions have a single one, in their exit block.
+    Ret,
 
     Comment(Option<String>),
 }
@@ -231,8 +289,10 @@ impl_operand!(
     Add(Add),
     Sub(Sub),
     Cmp(Cmp),
-    Jg(Jg),
+    Imul(Imul),
+    Jcc(Jcc),
     Jmp(Jmp),
+    Call(Call),
     Label(LabelRef),
     Block(BlockRef),
 );
@@ -278,24 +338,61 @@ impl Op {
         .into()
     }
 
-    pub fn jg<D: Into<LabelRef>>(target: D) -> Self {
-        Jg { dst: target.into() }.into()
+    pub fn imul<L: Into<Location>, R: Into<Location>>(lhs: L, rhs: R) -> Self {
+        Imul {
+            lhs: lhs.into(),
+            rhs: rhs.into(),
+        }
+        .into()
+    }
+
+    pub fn neg<L: Into<Location>>(l: L) -> Self {
+        Self::Neg(l.into())
+    }
+
+    pub fn cqo() -> Self {
+        Self::Cqo
+    }
+
+    pub fn idiv<L: Into<Location>>(l: L) -> Self {
+        Self::Idiv(l.into())
+    }
+
+    pub fn jcc<D: Into<LabelRef>>(cond: Cond, target: D) -> Self {
+        Jcc {
+            cond,
+            dst: target.into(),
+        }
+        .into()
     }
 
     pub fn jmp<D: Into<LabelRef>>(target: D) -> Self {
         Jmp { dst: target.into() }.into()
     }
 
-    pub fn label(l: LabelRef) -> Self {
-        l.into()
+    pub fn call<N: Into<String>>(target: N, args: Vec<Location>, dst: Option<Location>) -> Self {
+        Call {
+            target: target.into(),
+            args,
+            dst,
+        }
+        .into()
+    }
+
+    pub fn push<L: Into<Location>>(l: L) -> Self {
+        Self::Push(l.into())
+    }
+
+    pub fn pop<L: Into<Location>>(l: L) -> Self {
+        Self::Pop(l.into())
     }
 
-    pub fn ret_none() -> Self {
-        Self::Ret(None)
+    pub fn label(l: LabelRef) -> Self {
+        l.into()
     }
 
-    pub fn ret_some<L: Into<Location>>(l: L) -> Self {
-        Self::Ret(Some(l.into()))
+    pub fn ret() -> Self {
+        Self::Ret
     }
 
     pub fn comment<N: Into<String>>(n: N) -> Self {
@@ -333,16 +430,69 @@ pub struct Cmp {
     pub rhs: Location,
 }
 
+/// Signed multiplication, truncated to 64 bits
 #[derive(Debug)]
-pub struct Jg {
+pub struct Imul {
+    pub lhs: Location,
+    pub rhs: Location,
+}
+
+#[derive(Debug)]
+pub struct Jcc {
+    pub cond: Cond,
     pub dst: LabelRef,
 }
 
+/// How the operands of a `Cmp` relate, as `lhs <cond> rhs`. Signed
+/// conditions are named after less/greater, unsigned ones after
+/// below/above, like the x86 mnemonics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    E,
+    Ne,
+    L,
+    Le,
+    G,
+    Ge,
+    B,
+    Be,
+    A,
+    Ae,
+}
+
+impl Cond {
+    /// The condition that holds exactly when this one doesn't
+    pub fn negate(self) -> Self {
+        match self {
+            Self::E => Self::Ne,
+            Self::Ne => Self::E,
+            Self::L => Self::Ge,
+            Self::Le => Self::G,
+            Self::G => Self::Le,
+            Self::Ge => Self::L,
+            Self::B => Self::Ae,
+            Self::Be => Self::A,
+            Self::A => Self::Be,
+            Self::Ae => Self::B,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Jmp {
     pub dst: LabelRef,
 }
 
+/// Call to a function by symbol name, which may live in another object.
+/// Backends pass `args` and fetch the result as `frame::call` says.
+#[derive(Debug)]
+pub struct Call {
+    pub target: String,
+    pub args: Vec<Location>,
+    /// Where the return value goes, if anywhere
+    pub dst: Option<Location>,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum Location {
     Displaced(Displaced),
@@ -422,23 +572,28 @@ impl Reg {
     }
 }
 
-impl Into<Location> for LocalRef {
-    fn into(self) -> Location {
-        Location::Local(self)
+impl From<LocalRef> for Location {
+    fn from(l: LocalRef) -> Self {
+        Location::Local(l)
     }
 }
 
-impl Into<Location> for Reg {
-    fn into(self) -> Location {
-        Location::Register(self)
+impl From<Reg> for Location {
+    fn from(r: Reg) -> Self {
+        Location::Register(r)
     }
 }
 
 impl Location {
     fn is_displaced(self) -> bool {
+        matches!(self, Location::Displaced(_) | Location::Local(_))
+    }
+
+    /// Immediates only a move into a register can take, because they
+    /// don't fit in a sign-extended 32 bits
+    fn is_wide_imm(self) -> bool {
         match self {
-            Location::Displaced(_) => true,
-            Location::Local(_) => true,
+            Location::Imm64(v) => v < i64::from(i32::MIN) || v > i64::from(i32::MAX),
             _ => false,
         }
     }
@@ -453,9 +608,9 @@ impl Reg {
     }
 }
 
-impl Into<Location> for i64 {
-    fn into(self) -> Location {
-        Location::Imm64(self)
+impl From<i64> for Location {
+    fn from(v: i64) -> Self {
+        Location::Imm64(v)
     }
 }
 
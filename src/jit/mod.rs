@@ -0,0 +1,167 @@
+use crate::ir::{encode, Func};
+use crate::link;
+use crate::target::{self, CallingConvention, EntryExit, ObjectFormat, Target};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// The host target, except the entry point is called like any other
+/// function instead of being the first thing in the process
+struct JitTarget(&'static dyn Target);
+
+impl Target for JitTarget {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn object_format(&self) -> ObjectFormat {
+        self.0.object_format()
+    }
+
+    fn calling_convention(&self) -> CallingConvention {
+        self.0.calling_convention()
+    }
+
+    fn entry_symbol(&self) -> &'static str {
+        self.0.entry_symbol()
+    }
+
+    fn entry_exit(&self) -> EntryExit {
+        EntryExit::Ret
+    }
+
+    fn exe_extension(&self) -> &'static str {
+        self.0.exe_extension()
+    }
+
+    fn link_command(&self, obj: &Path, out: &Path) -> Command {
+        self.0.link_command(obj, out)
+    }
+}
+
+/// Machine code for a set of functions, mapped executable in our own
+/// address space
+pub struct Jit {
+    map: Map,
+    symbols: HashMap<String, usize>,
+}
+
+impl Jit {
+    pub fn new(funcs: &[&Func]) -> io::Result<Self> {
+        let target = JitTarget(target::host());
+        let code = encode::encode_all(funcs, &target)?;
+
+        let mut map = Map::new(code.text.len())?;
+        let text = link::resolve(&code, map.addr() as u64)?;
+        map.write(&text)?;
+
+        let symbols = code
+            .symbols
+            .iter()
+            .map(|s| (s.name.clone(), s.offset))
+            .collect();
+        Ok(Self { map, symbols })
+    }
+
+    /// Calls the function named `name`, which must take no arguments
+    pub fn call(&self, name: &str) -> io::Result<i64> {
+        let offset = match self.symbols.get(name) {
+            Some(&offset) => offset,
+            None => return Err(io::Error::other(format!("no function named `{}`", name))),
+        };
+
+        // safety: the code follows the host's calling convention, and
+        // `map` outlives the call
+        let f: extern "C" fn() -> i64 =
+            unsafe { std::mem::transmute(self.map.addr().wrapping_add(offset)) };
+        Ok(f())
+    }
+
+    pub fn call_entry(&self) -> io::Result<i64> {
+        self.call(target::host().entry_symbol())
+    }
+}
+
+/// An anonymous mapping, writable until `write` flips it to executable
+#[cfg(all(unix, target_arch = "x86_64"))]
+struct Map {
+    addr: *mut u8,
+    len: usize,
+}
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+impl Map {
+    fn new(len: usize) -> io::Result<Self> {
+        // mmap refuses empty mappings
+        let len = len.max(1);
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            addr: addr as *mut u8,
+            len,
+        })
+    }
+
+    fn addr(&self) -> *const u8 {
+        self.addr
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        assert!(bytes.len() <= self.len);
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), self.addr, bytes.len());
+            if libc::mprotect(
+                self.addr as *mut libc::c_void,
+                self.len,
+                libc::PROT_READ | libc::PROT_EXEC,
+            ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, target_arch = "x86_64"))]
+impl Drop for Map {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// The code is x86-64 whatever the host, so it only runs on x86-64
+#[cfg(not(all(unix, target_arch = "x86_64")))]
+struct Map;
+
+#[cfg(not(all(unix, target_arch = "x86_64")))]
+impl Map {
+    fn new(_len: usize) -> io::Result<Self> {
+        Err(io::Error::other(
+            "the JIT only supports x86-64 unix hosts for now, try --engine=interp",
+        ))
+    }
+
+    fn addr(&self) -> *const u8 {
+        unreachable!()
+    }
+
+    fn write(&mut self, _bytes: &[u8]) -> io::Result<()> {
+        unreachable!()
+    }
+}
@@ -0,0 +1,351 @@
+use crate::parser::{Error, Source, Span};
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while, take_while1},
+    combinator::{opt, recognize},
+    multi::many0,
+    sequence::{preceded, tuple},
+    IResult, Offset,
+};
+use std::fmt;
+
+type Res<'a, T> = IResult<&'a str, T>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    // keywords
+    Fn,
+    Pub,
+    Let,
+    Loop,
+    If,
+    Return,
+    Break,
+    Continue,
+
+    Identifier,
+    IntLit,
+    FloatLit,
+
+    // punctuation
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Comma,
+    Colon,
+    Semicolon,
+    Arrow,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Bang,
+    Eq,
+    EqEq,
+    BangEq,
+    PlusEq,
+    MinusEq,
+    StarEq,
+    SlashEq,
+    Gt,
+    GtEq,
+    Lt,
+    LtEq,
+
+    /// Zero-length, always the last token
+    Eof,
+}
+
+impl TokenKind {
+    /// How diagnostics refer to this kind of token
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Fn => "`fn`",
+            Self::Pub => "`pub`",
+            Self::Let => "`let`",
+            Self::Loop => "`loop`",
+            Self::If => "`if`",
+            Self::Return => "`return`",
+            Self::Break => "`break`",
+            Self::Continue => "`continue`",
+            Self::Identifier => "identifier",
+            Self::IntLit => "integer literal",
+            Self::FloatLit => "float literal",
+            Self::LParen => "`(`",
+            Self::RParen => "`)`",
+            Self::LBrace => "`{`",
+            Self::RBrace => "`}`",
+            Self::Comma => "`,`",
+            Self::Colon => "`:`",
+            Self::Semicolon => "`;`",
+            Self::Arrow => "`->`",
+            Self::Plus => "`+`",
+            Self::Minus => "`-`",
+            Self::Star => "`*`",
+            Self::Slash => "`/`",
+            Self::Bang => "`!`",
+            Self::Eq => "`=`",
+            Self::EqEq => "`==`",
+            Self::BangEq => "`!=`",
+            Self::PlusEq => "`+=`",
+            Self::MinusEq => "`-=`",
+            Self::StarEq => "`*=`",
+            Self::SlashEq => "`/=`",
+            Self::Gt => "`>`",
+            Self::GtEq => "`>=`",
+            Self::Lt => "`<`",
+            Self::LtEq => "`<=`",
+            Self::Eof => "end of file",
+        }
+    }
+}
+
+const KEYWORDS: &[(&str, TokenKind)] = &[
+    ("fn", TokenKind::Fn),
+    ("pub", TokenKind::Pub),
+    ("let", TokenKind::Let),
+    ("loop", TokenKind::Loop),
+    ("if", TokenKind::If),
+    ("return", TokenKind::Return),
+    ("break", TokenKind::Break),
+    ("continue", TokenKind::Continue),
+];
+
+/// Longest first, so `+=` isn't lexed as `+` then `=`
+const PUNCTUATION: &[(&str, TokenKind)] = &[
+    ("->", TokenKind::Arrow),
+    ("+=", TokenKind::PlusEq),
+    ("-=", TokenKind::MinusEq),
+    ("*=", TokenKind::StarEq),
+    ("/=", TokenKind::SlashEq),
+    (">=", TokenKind::GtEq),
+    ("<=", TokenKind::LtEq),
+    ("==", TokenKind::EqEq),
+    ("!=", TokenKind::BangEq),
+    ("(", TokenKind::LParen),
+    (")", TokenKind::RParen),
+    ("{", TokenKind::LBrace),
+    ("}", TokenKind::RBrace),
+    (",", TokenKind::Comma),
+    (":", TokenKind::Colon),
+    (";", TokenKind::Semicolon),
+    ("+", TokenKind::Plus),
+    ("-", TokenKind::Minus),
+    ("*", TokenKind::Star),
+    ("/", TokenKind::Slash),
+    ("!", TokenKind::Bang),
+    ("=", TokenKind::Eq),
+    (">", TokenKind::Gt),
+    ("<", TokenKind::Lt),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}({:?})", self.kind, self.span)
+    }
+}
+
+impl Token {
+    /// One line of a token dump: position, kind, and text
+    pub fn describe(&self, source: &Source) -> String {
+        let pos = source.position(self.span);
+        format!(
+            "{}:{}\t{:?}\t{:?}",
+            pos.line + 1,
+            pos.column + 1,
+            self.kind,
+            source.text(self.span)
+        )
+    }
+}
+
+/// Splits a whole source file into tokens, dropping whitespace and
+/// comments. The result always ends with a `TokenKind::Eof`.
+pub fn lex(source: &Source) -> Result<Vec<Token>, Error> {
+    let input = source.input.as_str();
+    let mut i = input;
+    let mut tokens = Vec::new();
+
+    loop {
+        i = match trivia(i) {
+            Ok((i, _)) => i,
+            Err(_) => unreachable!("trivia matches the empty string"),
+        };
+        if i.is_empty() {
+            break;
+        }
+
+        match token(i) {
+            Ok((rest, (kind, text))) => {
+                let span = source.span(input.offset(text), text.len());
+                tokens.push(Token { kind, span });
+                i = rest;
+            }
+            Err(_) => {
+                let c = i.chars().next().unwrap_or('\0');
+                let bad = source.span(input.offset(i), c.len_utf8());
+                return Err(Error::Diag(
+                    source
+                        .position(bad)
+                        .diag_err(format!("unexpected character {:?}", c))
+                        .build(),
+                ));
+            }
+        }
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        span: source.span(input.len(), 0),
+    });
+    Ok(tokens)
+}
+
+/// Whitespace (including newlines) and comments
+fn trivia(i: &str) -> Res<'_, Vec<&str>> {
+    many0(alt((
+        take_while1(|c| " \t\r\n".contains(c)),
+        recognize(preceded(tag("//"), take_while(|c| c != '\n'))),
+    )))(i)
+}
+
+/// The kind of the next token, and its text
+fn token(i: &str) -> Res<'_, (TokenKind, &str)> {
+    alt((float_lit, int_lit, word, punctuation))(i)
+}
+
+fn float_lit(i: &str) -> Res<'_, (TokenKind, &str)> {
+    let (i, text) = alt((
+        recognize(tuple((tag("."), digits))),
+        recognize(tuple((digits, tag("."), opt(digits)))),
+    ))(i)?;
+    Ok((i, (TokenKind::FloatLit, text)))
+}
+
+fn int_lit(i: &str) -> Res<'_, (TokenKind, &str)> {
+    let (i, text) = digits(i)?;
+    Ok((i, (TokenKind::IntLit, text)))
+}
+
+fn digits(i: &str) -> Res<'_, &str> {
+    let int_chars = "0123456789";
+    take_while1(move |c| int_chars.contains(c))(i)
+}
+
+static VALID_ID_CHARS: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_";
+
+/// An identifier, or a keyword if the whole word is one
+fn word(i: &str) -> Res<'_, (TokenKind, &str)> {
+    let (i, text) = take_while1(|c| VALID_ID_CHARS.contains(c))(i)?;
+
+    let kind = KEYWORDS
+        .iter()
+        .find(|(kw, _)| *kw == text)
+        .map(|(_, kind)| *kind)
+        .unwrap_or(TokenKind::Identifier);
+    Ok((i, (kind, text)))
+}
+
+fn punctuation(i: &str) -> Res<'_, (TokenKind, &str)> {
+    for (s, kind) in PUNCTUATION {
+        if i.starts_with(s) {
+            let (text, rest) = i.split_at(s.len());
+            return Ok((rest, (*kind, text)));
+        }
+    }
+    Err(nom::Err::Error((i, nom::error::ErrorKind::Tag)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::SourceMap;
+
+    /// Kind and text of every token but the final `Eof`
+    fn lexed(input: &str) -> Vec<(TokenKind, String)> {
+        let mut sources = SourceMap::default();
+        let file = sources.load_string(input).unwrap();
+        let source = sources.get(file);
+        let tokens = lex(source).unwrap();
+        assert_eq!(tokens.last().map(|t| t.kind), Some(TokenKind::Eof));
+        tokens[..tokens.len() - 1]
+            .iter()
+            .map(|t| (t.kind, source.text(t.span).to_string()))
+            .collect()
+    }
+
+    fn kinds(input: &str) -> Vec<TokenKind> {
+        lexed(input).into_iter().map(|(kind, _)| kind).collect()
+    }
+
+    #[test]
+    fn keywords_only_match_whole_words() {
+        use TokenKind::*;
+        assert_eq!(
+            lexed("letter = 1"),
+            [
+                (Identifier, "letter".to_string()),
+                (Eq, "=".to_string()),
+                (IntLit, "1".to_string()),
+            ]
+        );
+        assert_eq!(
+            kinds("let fnord returns if_ loop"),
+            [Let, Identifier, Identifier, Identifier, Loop]
+        );
+    }
+
+    #[test]
+    fn punctuation_takes_the_longest_match() {
+        use TokenKind::*;
+        assert_eq!(kinds("a += 1"), [Identifier, PlusEq, IntLit]);
+        assert_eq!(
+            kinds("fn f() -> i64"),
+            [Fn, Identifier, LParen, RParen, Arrow, Identifier]
+        );
+        assert_eq!(
+            kinds("a- >b<=c"),
+            [Identifier, Minus, Gt, Identifier, LtEq, Identifier]
+        );
+        assert_eq!(kinds("!==="), [BangEq, EqEq]);
+    }
+
+    #[test]
+    fn float_and_int_literals() {
+        use TokenKind::*;
+        assert_eq!(
+            kinds("1 1.5 .5 2. 10"),
+            [IntLit, FloatLit, FloatLit, FloatLit, IntLit]
+        );
+        assert_eq!(lexed("3.25")[0].1, "3.25");
+    }
+
+    #[test]
+    fn comments_and_whitespace_are_dropped() {
+        use TokenKind::*;
+        assert_eq!(kinds("a // b c\n\t\r\n d"), [Identifier, Identifier]);
+        assert_eq!(kinds("  // nothing else"), []);
+    }
+
+    #[test]
+    fn unexpected_characters_are_reported() {
+        let mut sources = SourceMap::default();
+        let file = sources.load_string("let a = 1;\nlet b = a $ 2;").unwrap();
+        let source = sources.get(file);
+        colored::control::set_override(false);
+        match lex(source) {
+            Err(Error::Diag(d)) => assert_eq!(
+                d.to_string(),
+                "<memory>:2:11: unexpected character '$'\nlet b = a $ 2;\n          ^\n"
+            ),
+            res => panic!("expected a diagnostic, got {:?}", res.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::ir::encode::Code;
+use crate::object::elf;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io;
+
+/// Links `code` into a static ELF executable, on its own: there are no
+/// libraries to pull in, so every call must land on one of our
+/// functions.
+pub fn link_elf(code: &Code, entry: &str) -> io::Result<Vec<u8>> {
+    let text_addr = elf::EXEC_BASE + elf::EXEC_TEXT_OFFSET;
+    let text = resolve(code, text_addr)?;
+
+    let entry = match code.symbols.iter().find(|s| s.name == entry) {
+        Some(s) => text_addr + s.offset as u64,
+        None => {
+            return Err(io::Error::other(format!(
+                "entry point `{}` is not defined",
+                entry
+            )))
+        }
+    };
+
+    Ok(elf::write_executable(&text, entry))
+}
+
+/// Returns a copy of `code.text`, loaded at `text_addr`, with every
+/// relocation applied
+pub fn resolve(code: &Code, text_addr: u64) -> io::Result<Vec<u8>> {
+    let addrs: HashMap<&str, u64> = code
+        .symbols
+        .iter()
+        .map(|s| (s.name.as_str(), text_addr + s.offset as u64))
+        .collect();
+
+    let mut text = code.text.clone();
+    for r in &code.relocs {
+        let target = match addrs.get(r.symbol.as_str()) {
+            Some(&addr) => addr,
+            None => {
+                return Err(io::Error::other(format!(
+                    "undefined reference to `{}`",
+                    r.symbol
+                )))
+            }
+        };
+
+        // pc-relative: S + A - P
+        let place = text_addr + r.offset as u64;
+        let disp = target as i64 + r.addend - place as i64;
+        let disp = i32::try_from(disp)
+            .map_err(|_| io::Error::other(format!("call to `{}` is out of range", r.symbol)))?;
+        text[r.offset..r.offset + 4].copy_from_slice(&disp.to_le_bytes());
+    }
+
+    Ok(text)
+}
@@ -1,83 +1,172 @@
 pub mod ast;
+pub mod driver;
 pub mod ir;
+pub mod jit;
+pub mod lexer;
+pub mod link;
 pub mod middle;
+pub mod object;
+pub mod par;
 pub mod parser;
+pub mod repl;
+pub mod target;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use driver::{Emit, Engine, Linker};
 use ir::*;
+use std::path::PathBuf;
 
 fn main() -> Result<(), parser::Error> {
     let matches = App::new("Morning Language")
         .version("0.1.0")
         .author("@fasterthanlime's twitch chat")
         .about("?")
-        .arg(
-            Arg::with_name("INPUT")
-                .help("Sets the input file to use")
-                .required(true)
-                .index(1),
-        )
+        .setting(AppSettings::SubcommandRequiredElseHelp)
         .arg(
             Arg::with_name("v")
                 .short("v")
                 .multiple(true)
                 .help("Sets the level of verbosity"),
         )
+        .subcommand(
+            SubCommand::with_name("build")
+                .about("Compiles a file to an executable, or to the stages given with --emit")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .takes_value(true)
+                        .possible_values(&["x86_64-linux", "x86_64-windows"])
+                        .help("Sets the platform to build for (defaults to the host)"),
+                )
+                .arg(
+                    Arg::with_name("emit")
+                        .long("emit")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .use_delimiter(true)
+                        .possible_values(Emit::NAMES)
+                        .help("Sets which outputs to write (defaults to exe)"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("Sets the output path"),
+                )
+                .arg(
+                    Arg::with_name("linker")
+                        .long("linker")
+                        .takes_value(true)
+                        .possible_values(Linker::NAMES)
+                        .help("Sets how executables are linked (defaults to builtin for ELF)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Compiles a file in memory and runs it, exiting with its result")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file to use")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("engine")
+                        .long("engine")
+                        .takes_value(true)
+                        .possible_values(Engine::NAMES)
+                        .help("Sets how the code is executed (defaults to jit)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repl")
+                .about("Reads declarations, statements and expressions interactively"),
+        )
+        .subcommand(
+            SubCommand::with_name("demangle")
+                .about("Maps symbols back to source names, filtering stdin if none are given")
+                .arg(
+                    Arg::with_name("SYMBOL")
+                        .help("Sets the symbols to demangle")
+                        .multiple(true),
+                ),
+        )
         .get_matches();
 
+    match matches.subcommand() {
+        ("build", Some(matches)) => build(matches),
+        ("run", Some(matches)) => run(matches),
+        ("repl", Some(_)) => Ok(repl::run()?),
+        ("demangle", Some(matches)) => Ok(demangle(matches)?),
+        _ => unreachable!("clap should require a subcommand"),
+    }
+}
+
+fn build(matches: &ArgMatches) -> Result<(), parser::Error> {
     let input = matches.value_of("INPUT").unwrap();
     println!("Compiling: {}", input);
 
-    let source = parser::Source::from_path(input)?;
-    let unit = parser::parse(source)?;
-    println!("AST: {:#?}", unit);
+    let target = match matches.value_of("target") {
+        Some(name) => target::by_name(name).expect("clap should validate target names"),
+        None => target::host(),
+    };
+    let emit = match matches.values_of("emit") {
+        Some(names) => names
+            .map(|name| Emit::from_name(name).expect("clap should validate emit kinds"))
+            .collect(),
+        None => vec![Emit::Exe],
+    };
+    let linker = matches
+        .value_of("linker")
+        .map(|name| Linker::from_name(name).expect("clap should validate linker names"));
+
+    driver::build(&driver::Options {
+        input: input.into(),
+        output: matches.value_of("output").map(PathBuf::from),
+        emit,
+        target,
+        linker,
+    })
+}
 
-    {
-        let mut buf: Vec<u8> = Vec::new();
-        middle::transform(&mut buf, &unit)?;
-
-        let asm_path = "./samples/hello.asm";
-        std::fs::write(asm_path, buf)?;
-
-        let status = std::process::Command::new("bat")
-            .arg(asm_path)
-            .status()
-            .expect("bat should run");
-        if !status.success() {
-            panic!("bat failed with {:?}", status)
-        }
+fn run(matches: &ArgMatches) -> Result<(), parser::Error> {
+    let input = matches.value_of("INPUT").unwrap();
+    let engine = matches
+        .value_of("engine")
+        .map(|name| Engine::from_name(name).expect("clap should validate engine names"))
+        .unwrap_or(Engine::Jit);
+    let result = driver::run(input.as_ref(), engine)?;
+    println!("{}", result);
+    std::process::exit(result as i32)
+}
 
-        let status = std::process::Command::new("nasm")
-            .arg("-f")
-            .arg("win64")
-            .arg("-o")
-            .arg("./samples/hello.obj")
-            .arg("./samples/hello.asm")
-            .status()
-            .expect("nasm should run");
-        if !status.success() {
-            panic!("nasm failed with {:?}", status)
-        }
+fn demangle(matches: &ArgMatches) -> std::io::Result<()> {
+    use std::io::BufRead;
 
-        let link_path = r#"D:\Programs\Microsoft Visual Studio\2019\Community\VC\Tools\MSVC\14.23.28105\bin\Hostx64\x64\link.exe"#;
-        let status = std::process::Command::new(link_path)
-            .arg("/SUBSYSTEM:CONSOLE")
-            .arg("/ENTRY:_start")
-            .arg(r#"samples\hello.obj"#)
-            .arg(r#"/OUT:samples\hello.exe"#)
-            .status()
-            .expect("link should run");
-        if !status.success() {
-            panic!("link failed with {:?}", status)
+    match matches.values_of("SYMBOL") {
+        Some(symbols) => {
+            for symbol in symbols {
+                println!("{}", middle::mangle::demangle_text(symbol));
+            }
+        }
+        None => {
+            for line in std::io::stdin().lock().lines() {
+                println!("{}", middle::mangle::demangle_text(&line?));
+            }
         }
-
-        println!("Compiled to samples/hello.exe");
     }
-
     Ok(())
 }
 
-#[allow(dead_code)]
+/// `samples/hello.rs`, written directly in IR
+#[cfg(test)]
 fn manual_ir() -> ir::Func {
     let mut main = Func::new("_start");
     main.public = true;
@@ -110,13 +199,41 @@ fn manual_ir() -> ir::Func {
         entry.push_op(f, Op::cmp(x, 10));
 
         // break
-        entry.push_op(f, Op::jg(loopend));
+        entry.push_op(f, Op::jcc(Cond::G, loopend));
         // continue (implicit)
         entry.push_op(f, Op::jmp(loopstart));
 
         entry.push_op(f, loopend);
-        entry.push_op(f, Op::ret_some(y));
+        entry.push_op(f, Op::mov(Reg::RAX, y));
+        entry.push_op(f, Op::ret());
     }
 
     main
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The sample and the IR it was written from compute the same sum
+    #[test]
+    fn hello_sample_matches_manual_ir() {
+        let target = target::host();
+        let run = |funcs: &[&Func]| {
+            ir::interp::Interp::new(funcs, target)
+                .call(target.entry_symbol(), &[])
+                .unwrap()
+        };
+
+        let manual = manual_ir();
+        assert_eq!(run(&[&manual]), 55);
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/samples/hello.rs");
+        let mut sources = parser::SourceMap::default();
+        let file = sources.load_path(path).unwrap();
+        let unit = parser::parse(sources.get(file)).unwrap();
+        let funcs = middle::transform(&unit, &sources, target).unwrap();
+        let v: Vec<_> = funcs.iter().collect();
+        assert_eq!(run(&v), 55);
+    }
+}
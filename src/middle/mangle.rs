@@ -0,0 +1,92 @@
+//! Symbol names for functions.
+//!
+//! `pub` functions keep their source name, since that's what other
+//! objects (and the OS loader) look them up by. Everything else is
+//! mangled from its path: `_M`, then each segment prefixed with its
+//! length, then `E`. `foo` becomes `_M3fooE`, and `a::foo`, once there
+//! are modules, `_M1a3fooE`. Identifiers can't start with a digit, so
+//! this can always be read back. `pub` names that read back like that
+//! are rejected (see `middle::transform`), so the two never clash.
+
+const PREFIX: &str = "_M";
+const SUFFIX: &str = "E";
+
+pub fn mangle(path: &[&str]) -> String {
+    let mut res = String::from(PREFIX);
+    for segment in path {
+        res.push_str(&segment.len().to_string());
+        res.push_str(segment);
+    }
+    res.push_str(SUFFIX);
+    res
+}
+
+/// The source path of a mangled symbol, like `a::foo`
+pub fn demangle(symbol: &str) -> Option<String> {
+    let (path, len) = demangle_prefix(symbol)?;
+    if len == symbol.len() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Demangles every symbol found in `text`, including the ones labels
+/// are named after (`.L_M3fooE_2` becomes `.Lfoo_2`)
+pub fn demangle_text(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(index) = rest.find(PREFIX) {
+        let (before, candidate) = rest.split_at(index);
+        res.push_str(before);
+
+        let at_boundary = match res.chars().last() {
+            Some(c) => !is_ident_char(c) || res.ends_with(".L"),
+            None => true,
+        };
+        match demangle_prefix(candidate) {
+            Some((path, len)) if at_boundary => {
+                res.push_str(&path);
+                rest = &candidate[len..];
+            }
+            _ => {
+                res.push_str(PREFIX);
+                rest = &candidate[PREFIX.len()..];
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+/// Demangles the symbol `s` starts with, returning its path and how many
+/// bytes it took up
+fn demangle_prefix(s: &str) -> Option<(String, usize)> {
+    let mut rest = s.strip_prefix(PREFIX)?;
+    let mut segments = Vec::new();
+
+    loop {
+        if let Some(after) = rest.strip_prefix(SUFFIX) {
+            if segments.is_empty() {
+                return None;
+            }
+            return Some((segments.join("::"), s.len() - after.len()));
+        }
+
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let len: usize = rest[..digits].parse().ok()?;
+        let segment = rest[digits..].get(..len)?;
+        if len == 0 || !segment.chars().all(is_ident_char) {
+            return None;
+        }
+        segments.push(segment);
+        rest = &rest[digits + len..];
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
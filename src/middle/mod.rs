@@ -1,33 +1,77 @@
-use crate::{ast, ir, parser::Error};
+pub mod mangle;
+
+use crate::{
+    ast, ir, par,
+    parser::{Error, SourceMap, Span},
+    target::Target,
+};
 use std::collections::HashMap;
-use std::io;
+use std::convert::TryFrom;
 
 type Result<T> = std::result::Result<T, Error>;
 
 pub struct File {}
 
-pub fn transform(w: &mut dyn io::Write, u: &ast::Unit) -> Result<()> {
-    let mut funs = Vec::new();
+/// Functions of a unit by name, for calls to look up
+type Functions<'a> = HashMap<&'a str, &'a ast::FDecl>;
 
+/// Lowers every function, in parallel. The first error (in source
+/// order) wins.
+pub fn transform(u: &ast::Unit, sources: &SourceMap, target: &dyn Target) -> Result<Vec<ir::Func>> {
+    let mut funs = Functions::new();
     for af in &u.funs {
-        funs.push(transform_fdecl(af)?);
-    }
-
-    let v: Vec<_> = funs.iter().collect();
-    ir::emit::emit_all(w, &v[..])?;
+        check_symbol(sources, target, af)?;
 
-    Ok(())
+        let name = af.name.value.as_str();
+        if let Some(first) = funs.insert(name, af) {
+            let previous = first
+                .name
+                .loc
+                .position(sources)
+                .diag_info(format!("previous definition of `{}` here", name))
+                .build();
+            return Err(Error::Diag(
+                af.name
+                    .loc
+                    .position(sources)
+                    .diag_err(format!("the name `{}` is defined multiple times", name))
+                    .related(previous)
+                    .build(),
+            ));
+        }
+    }
+    par::try_map(&u.funs, |af| transform_fdecl(&funs, sources, target, af))
 }
 
-struct Stack {
+struct Stack<'a> {
     f: ir::Func,
     items: Vec<Item>,
+    funs: &'a Functions<'a>,
+    /// Where the unit's spans point, for diagnostics
+    sources: &'a SourceMap,
+    /// Where return values go
+    ret_reg: ir::Reg,
+    /// Start of the exit block, which every `return` jumps to
+    exit: ir::LabelRef,
 }
 
-impl Stack {
-    pub fn new(f: ir::Func) -> Self {
+impl<'a> Stack<'a> {
+    pub fn new(
+        mut f: ir::Func,
+        funs: &'a Functions<'a>,
+        sources: &'a SourceMap,
+        target: &dyn Target,
+    ) -> Self {
         let items = vec![Scope::new(f.entry).into()];
-        Self { f, items }
+        let exit = f.entry.new_label(&mut f);
+        Self {
+            f,
+            items,
+            funs,
+            sources,
+            ret_reg: target.calling_convention().return_reg(),
+            exit,
+        }
     }
 
     pub fn f(&mut self) -> &mut ir::Func {
@@ -49,6 +93,25 @@ impl Stack {
         self.scope().block.borrow_mut(&mut self.f)
     }
 
+    /// A fresh local for an intermediate value
+    pub fn temp(&mut self) -> ir::LocalRef {
+        let name = format!("%{}", self.f.locals.len());
+        self.f.push_local(name, ir::Type::I64)
+    }
+
+    pub fn new_label(&mut self) -> ir::LabelRef {
+        let block = self.scope().block;
+        block.new_label(&mut self.f)
+    }
+
+    /// The loop a `break` or `continue` would apply to
+    pub fn innermost_loop(&self) -> Option<&Loop> {
+        self.items.iter().rev().find_map(|i| match i {
+            Item::Loop(l) => Some(l),
+            _ => None,
+        })
+    }
+
     pub fn lookup(&self, name: &str) -> Option<ir::LocalRef> {
         self.items.iter().rev().find_map(|i| match i {
             Item::Scope(s) => s.bindings.get(name).map(|x| *x),
@@ -109,66 +172,197 @@ impl Into<Item> for Scope {
     }
 }
 
-fn transform_fdecl(af: &ast::FDecl) -> Result<ir::Func> {
-    let mut f = ir::Func::new(af.name.value.clone());
+/// Rejects functions whose symbol wouldn't be what it should: `pub`
+/// names that could be taken for a mangled symbol, and an entry point
+/// that would get mangled
+fn check_symbol(sources: &SourceMap, target: &dyn Target, af: &ast::FDecl) -> Result<()> {
+    let name = af.name.value.as_str();
+    if name == target.entry_symbol() && !af.public {
+        return Err(Error::Diag(
+            af.name
+                .loc
+                .position(sources)
+                .diag_err(format!("entry point `{}` must be `pub`", name))
+                .note("private functions get mangled symbols, which the loader can't find".into())
+                .build(),
+        ));
+    }
+
+    match mangle::demangle(name) {
+        Some(path) if af.public => Err(Error::Diag(
+            af.name
+                .loc
+                .position(sources)
+                .diag_err(format!(
+                    "`pub` function `{}` is named like a mangled symbol",
+                    name
+                ))
+                .note(format!(
+                    "it could clash with the symbol of a private `{}`",
+                    path
+                ))
+                .build(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Name of the symbol a function is compiled to, see `mangle`
+pub fn symbol_name(af: &ast::FDecl) -> String {
+    if af.public {
+        af.name.value.clone()
+    } else {
+        mangle::mangle(&[&af.name.value])
+    }
+}
+
+fn transform_fdecl(
+    funs: &Functions,
+    sources: &SourceMap,
+    target: &dyn Target,
+    af: &ast::FDecl,
+) -> Result<ir::Func> {
+    let mut f = ir::Func::new(symbol_name(af));
     f.public = af.public;
-    let mut st = Stack::new(f);
+    f.ret = af
+        .ret
+        .as_ref()
+        .map(|t| resolve_type(sources, t))
+        .transpose()?;
+    let mut st = Stack::new(f, funs, sources, target);
+
+    for param in &af.params {
+        let typ = resolve_type(sources, &param.typ)?;
+        let local = st.f().push_param(param.name.value.clone(), typ);
+        st.scope().add_binding(param.name.value.clone(), local);
+    }
 
     for stat in &af.body.items {
         transform_stat(&mut st, stat)?;
     }
 
+    // the tail is what the body evaluates to, like a last `return`
+    let (ret_reg, exit_label) = (st.ret_reg, st.exit);
+    let tail = af.body.tail.as_deref();
+    if let Some(tail) = tail {
+        let value = transform_expr(&mut st, tail)?;
+        check_return(&st, tail.loc(), Some(tail))?;
+        if st.f.ret.is_some() {
+            st.block().push_op(ir::Op::mov(ret_reg, value));
+        }
+    }
+
+    // falling off the end returns 0, for the sake of entry points
+    let returns_tail = tail.is_some() && st.f.ret.is_some();
+    if !returns_tail && !always_returns(&af.body) {
+        if let Some(typ) = st.f.ret {
+            let body = af.body.loc;
+            let closing_brace = Span {
+                lo: body.hi - 1,
+                ..body
+            };
+            return Err(Error::Diag(
+                closing_brace
+                    .position(st.sources)
+                    .diag_err(format!("expected `{}`, found `()`", typ.name()))
+                    .note("the body can reach its end without returning a value".into())
+                    .build(),
+            ));
+        }
+        st.block().push_op(ir::Op::mov(ret_reg, 0));
+    }
+    let exit = st.f().push_block();
+    exit.push_op(st.f(), exit_label);
+    exit.push_op(st.f(), ir::Op::ret());
+    st.block().push_op(exit);
+
     Ok(st.into_inner())
 }
 
-fn transform_stat(st: &mut Stack, stat: &ast::Statement) -> Result<()> {
-    match stat {
-        ast::Statement::Expr(ex) => match ex {
-            ast::Expr::Bexp(bexp) => match bexp.operator {
-                ast::Bop::Assign => match bexp.lhs.as_ref() {
-                    ast::Expr::Identifier(id) => {
-                        if let Some(local) = st.lookup(&id.value) {
-                            st.block().push_op(ir::Op::comment(format!(
-                                "found {:?} at slot {:?}",
-                                &id.value, local
-                            )))
-                        } else {
-                            return Err(Error::Diag(
-                                id.loc
-                                    .position()
-                                    .diag_err(format!(
-                                        "cannot find value `{}` in this scope",
-                                        id.value
-                                    ))
-                                    .build(),
-                            ));
-                        }
-                        st.block().push_op(ir::Op::mov(ir::Reg::RAX, 420));
-                    }
-                    _ => {}
-                },
-                _ => {}
+/// The type a type reference names
+fn resolve_type(sources: &SourceMap, t: &ast::TypeRef) -> Result<ir::Type> {
+    match t.id.value.as_str() {
+        "i64" => Ok(ir::Type::I64),
+        name => Err(Error::Diag(
+            t.id.loc
+                .position(sources)
+                .diag_err(format!("cannot find type `{}` in this scope", name))
+                .build(),
+        )),
+    }
+}
+
+/// The type of an expression's value, `None` if it has none
+fn type_of(st: &Stack, ex: &ast::Expr) -> Option<ir::Type> {
+    match ex {
+        ast::Expr::Block(_) => None,
+        ast::Expr::Call(call) => match call.target.as_ref() {
+            ast::Expr::Identifier(id) => match st.funs.get(id.value.as_str()) {
+                Some(fun) => fun
+                    .ret
+                    .as_ref()
+                    .and_then(|t| resolve_type(st.sources, t).ok()),
+                // lowering the call reports this
+                None => Some(ir::Type::I64),
             },
-            _ => {}
+            _ => Some(ir::Type::I64),
         },
+        _ => Some(ir::Type::I64),
+    }
+}
+
+/// Whether control never reaches the end of `block`: it returns, or
+/// loops forever, on every path
+fn always_returns(block: &ast::Block) -> bool {
+    block.items.iter().any(|stat| match stat {
+        ast::Statement::Return(_) => true,
+        ast::Statement::Block(b) => always_returns(b),
+        ast::Statement::Loop(l) => !breaks(&l.body),
+        _ => false,
+    })
+}
+
+/// Whether `block` has a `break` out of the loop it's the body of
+fn breaks(block: &ast::Block) -> bool {
+    block.items.iter().any(|stat| match stat {
+        ast::Statement::Break(_) => true,
+        ast::Statement::Block(b) => breaks(b),
+        ast::Statement::If(i) => breaks(&i.body),
+        // breaks in there are that loop's business
+        ast::Statement::Loop(_) => false,
+        _ => false,
+    })
+}
+
+fn transform_stat(st: &mut Stack, stat: &ast::Statement) -> Result<()> {
+    match stat {
+        ast::Statement::Expr(ex) => {
+            transform_expr(st, ex)?;
+        }
         ast::Statement::VDecl(vd) => {
             st.block()
                 .push_op(ir::Op::comment(format!("vdecl {}", vd.name.value)));
-            let local = st.f().push_local(vd.name.value.clone(), ir::Type::I64);
+            // lowered first, so `let x = x;` refers to the outer `x`
+            let value = match vd.value.as_ref() {
+                Some(value) => Some(transform_expr(st, value)?),
+                None => None,
+            };
+
+            let typ = match vd.typ.as_ref() {
+                Some(t) => resolve_type(st.sources, t)?,
+                None => ir::Type::I64,
+            };
+            let local = st.f().push_local(vd.name.value.clone(), typ);
             st.scope().add_binding(vd.name.value.clone(), local);
 
-            if let Some(value) = vd.value.as_ref() {
-                match value {
-                    ast::Expr::IntLit(ast::IntLit { value, .. }) => {
-                        st.block().push_op(ir::Op::mov(local, *value))
-                    }
-                    _ => {}
-                }
+            if let Some(value) = value {
+                st.block().push_op(ir::Op::mov(local, value))
             }
         }
         ast::Statement::Loop(l) => {
-            let continue_label = st.block().new_label();
-            let break_label = st.block().new_label();
+            let block = st.scope().block;
+            let continue_label = block.new_label(st.f());
+            let break_label = block.new_label(st.f());
 
             st.push(
                 Item::Loop(Loop {
@@ -191,7 +385,426 @@ fn transform_stat(st: &mut Stack, stat: &ast::Statement) -> Result<()> {
                 },
             )?;
         }
-        _ => {}
+        ast::Statement::Return(ret) => {
+            // lowered first, so a call to nothing is reported as that
+            let value = match ret.expr.as_ref() {
+                Some(ex) => transform_expr(st, ex)?,
+                None => 0.into(),
+            };
+            let loc = ret.expr.as_ref().map_or(&ret.loc, |ex| ex.loc());
+            check_return(st, loc, ret.expr.as_ref())?;
+            let (ret_reg, exit) = (st.ret_reg, st.exit);
+            st.block().push_op(ir::Op::mov(ret_reg, value));
+            st.block().push_op(ir::Op::jmp(exit));
+        }
+        ast::Statement::Block(block) => transform_block(st, block)?,
+        ast::Statement::If(ifs) => {
+            let skip = st.new_label();
+            jump_unless(st, &ifs.cond, skip)?;
+            transform_block(st, &ifs.body)?;
+            st.block().push_op(skip);
+        }
+        ast::Statement::Continue(loc) | ast::Statement::Break(loc) => {
+            let is_break = matches!(stat, ast::Statement::Break(_));
+            let l = st.innermost_loop().ok_or_else(|| {
+                let keyword = if is_break { "break" } else { "continue" };
+                Error::Diag(
+                    loc.position(st.sources)
+                        .diag_err(format!("`{}` outside of a loop", keyword))
+                        .build(),
+                )
+            })?;
+            let dst = if is_break {
+                l.break_label
+            } else {
+                l.continue_label
+            };
+            st.block().push_op(ir::Op::jmp(dst));
+        }
+        ast::Statement::Error(_) => unreachable!("units with syntax errors aren't lowered"),
     }
     Ok(())
 }
+
+/// Checks what a function returns, with `return` or as the tail of its
+/// body, against its return type
+fn check_return(st: &Stack, loc: &Span, ex: Option<&ast::Expr>) -> Result<()> {
+    let expected = st.f.ret;
+    let found = ex.and_then(|ex| type_of(st, ex));
+    if expected == found {
+        return Ok(());
+    }
+
+    Err(Error::Diag(
+        loc.position(st.sources)
+            .diag_err(format!(
+                "expected `{}`, found `{}`",
+                expected.map_or("()", ir::Type::name),
+                found.map_or("()", ir::Type::name)
+            ))
+            .build(),
+    ))
+}
+
+/// Lowers a block's statements, in a scope of their own. Only a
+/// function's body has a value, so any other tail must be `()`.
+fn transform_block(st: &mut Stack, block: &ast::Block) -> Result<()> {
+    let inner = st.f().push_block();
+    st.block().push_op(inner);
+    st.push(Scope::new(inner), |st| -> Result<()> {
+        for stat in &block.items {
+            transform_stat(st, stat)?;
+        }
+
+        if let Some(tail) = block.tail.as_deref() {
+            transform_expr(st, tail)?;
+            if let Some(typ) = type_of(st, tail) {
+                return Err(Error::Diag(
+                    tail.loc()
+                        .position(st.sources)
+                        .diag_err(format!("expected `()`, found `{}`", typ.name()))
+                        .note("only a function's body has a value, add a `;` to discard it".into())
+                        .build(),
+                ));
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Lowers an expression to a location holding its value
+fn transform_expr(st: &mut Stack, ex: &ast::Expr) -> Result<ir::Location> {
+    match ex {
+        ast::Expr::IntLit(il) => {
+            // only moves into a register take 64-bit immediates, so
+            // anything wider than 32 bits gets its own local
+            if i32::try_from(il.value).is_ok() {
+                return Ok(il.value.into());
+            }
+            let tmp = st.temp();
+            st.block().push_op(ir::Op::mov(tmp, il.value));
+            Ok(tmp.into())
+        }
+        ast::Expr::FloatLit(fl) => Err(Error::Diag(
+            fl.loc
+                .position(st.sources)
+                .diag_err("floating-point numbers are not supported yet".into())
+                .build(),
+        )),
+        ast::Expr::Identifier(id) => Ok(lookup(st, id)?.into()),
+        ast::Expr::Bexp(bexp) => transform_bexp(st, bexp),
+        ast::Expr::Uexp(uexp) => transform_uexp(st, uexp),
+        ast::Expr::Call(call) => transform_call(st, call),
+        ast::Expr::Block(block) => {
+            // blocks don't produce a value (yet), they just run
+            transform_block(st, block)?;
+            Ok(0.into())
+        }
+    }
+}
+
+/// Like `transform_expr`, but the value is copied if evaluating more
+/// expressions could change it, like a variable that gets assigned to
+fn transform_value(st: &mut Stack, ex: &ast::Expr) -> Result<ir::Location> {
+    let value = transform_expr(st, ex)?;
+    match value {
+        ir::Location::Imm64(_) => Ok(value),
+        _ => {
+            let tmp = st.temp();
+            st.block().push_op(ir::Op::mov(tmp, value));
+            Ok(tmp.into())
+        }
+    }
+}
+
+fn lookup(st: &Stack, id: &ast::Id) -> Result<ir::LocalRef> {
+    st.lookup(&id.value).ok_or_else(|| {
+        Error::Diag(
+            id.loc
+                .position(st.sources)
+                .diag_err(format!("cannot find value `{}` in this scope", id.value))
+                .build(),
+        )
+    })
+}
+
+fn transform_bexp(st: &mut Stack, bexp: &ast::Bexp) -> Result<ir::Location> {
+    use ast::Bop;
+
+    if let Bop::Assign = bexp.operator {
+        let local = match bexp.lhs.as_ref() {
+            ast::Expr::Identifier(id) => lookup(st, id)?,
+            lhs => {
+                return Err(Error::Diag(
+                    lhs.loc()
+                        .position(st.sources)
+                        .diag_err("invalid left-hand side of assignment".into())
+                        .build(),
+                ))
+            }
+        };
+        let value = transform_expr(st, &bexp.rhs)?;
+        st.block().push_op(ir::Op::mov(local, value));
+        return Ok(local.into());
+    }
+
+    // the left-hand side is evaluated first, and kept from changing
+    // while the right-hand side is
+    let tmp = st.temp();
+    let lhs = transform_expr(st, &bexp.lhs)?;
+    st.block().push_op(ir::Op::mov(tmp, lhs));
+    let rhs = transform_expr(st, &bexp.rhs)?;
+
+    match bexp.operator {
+        Bop::Plus => st.block().push_op(ir::Op::add(tmp, rhs)),
+        Bop::Minus => st.block().push_op(ir::Op::sub(tmp, rhs)),
+        Bop::Mul => st.block().push_op(ir::Op::imul(tmp, rhs)),
+        Bop::Div => {
+            let divisor = match rhs {
+                // `idiv` has no immediate form
+                ir::Location::Imm64(_) => {
+                    st.block().push_op(ir::Op::mov(ir::Reg::RCX, rhs));
+                    ir::Reg::RCX.into()
+                }
+                _ => rhs,
+            };
+            st.block().push_op(ir::Op::mov(ir::Reg::RAX, tmp));
+            st.block().push_op(ir::Op::cqo());
+            st.block().push_op(ir::Op::idiv(divisor));
+            st.block().push_op(ir::Op::mov(tmp, ir::Reg::RAX));
+        }
+        Bop::Eq | Bop::Ne | Bop::Gt | Bop::GtEq | Bop::Lt | Bop::LtEq => {
+            let cond = condition(&bexp.operator).expect("comparisons have a condition");
+            compare(st, tmp.into(), rhs);
+            set_if(st, tmp, cond);
+        }
+        Bop::Assign => unreachable!("assignments are lowered above"),
+    }
+    Ok(tmp.into())
+}
+
+/// The condition a comparison operator checks for
+fn condition(operator: &ast::Bop) -> Option<ir::Cond> {
+    use ast::Bop;
+
+    Some(match operator {
+        Bop::Eq => ir::Cond::E,
+        Bop::Ne => ir::Cond::Ne,
+        Bop::Gt => ir::Cond::G,
+        Bop::GtEq => ir::Cond::Ge,
+        Bop::Lt => ir::Cond::L,
+        Bop::LtEq => ir::Cond::Le,
+        _ => return None,
+    })
+}
+
+/// Compares `lhs` to `rhs`, for a `Jcc` to act on
+fn compare(st: &mut Stack, lhs: ir::Location, rhs: ir::Location) {
+    st.block().push_op(ir::Op::mov(ir::Reg::RAX, lhs));
+    st.block().push_op(ir::Op::cmp(ir::Reg::RAX, rhs));
+}
+
+/// Sets `dst` to 1 if the last comparison meets `cond`, and to 0
+/// otherwise
+fn set_if(st: &mut Stack, dst: ir::LocalRef, cond: ir::Cond) {
+    let done = st.new_label();
+    st.block().push_op(ir::Op::mov(dst, 1));
+    st.block().push_op(ir::Op::jcc(cond, done));
+    st.block().push_op(ir::Op::mov(dst, 0));
+    st.block().push_op(done);
+}
+
+/// Jumps to `dst` if `cond` is false: comparisons branch on their
+/// operands directly, anything else is false when it's 0
+fn jump_unless(st: &mut Stack, cond: &ast::Expr, dst: ir::LabelRef) -> Result<()> {
+    if let ast::Expr::Bexp(bexp) = cond {
+        if let Some(c) = condition(&bexp.operator) {
+            let lhs = transform_value(st, &bexp.lhs)?;
+            let rhs = transform_expr(st, &bexp.rhs)?;
+            compare(st, lhs, rhs);
+            st.block().push_op(ir::Op::jcc(c.negate(), dst));
+            return Ok(());
+        }
+    }
+
+    let value = transform_expr(st, cond)?;
+    compare(st, value, 0.into());
+    st.block().push_op(ir::Op::jcc(ir::Cond::E, dst));
+    Ok(())
+}
+
+fn transform_uexp(st: &mut Stack, uexp: &ast::Uexp) -> Result<ir::Location> {
+    let tmp = st.temp();
+    let operand = transform_expr(st, &uexp.operand)?;
+
+    match uexp.operator {
+        ast::Uop::Neg => {
+            st.block().push_op(ir::Op::mov(tmp, operand));
+            st.block().push_op(ir::Op::neg(tmp));
+        }
+        ast::Uop::Not => {
+            compare(st, operand, 0.into());
+            set_if(st, tmp, ir::Cond::E);
+        }
+    }
+    Ok(tmp.into())
+}
+
+fn transform_call(st: &mut Stack, call: &ast::Call) -> Result<ir::Location> {
+    let id = match call.target.as_ref() {
+        ast::Expr::Identifier(id) => id,
+        target => {
+            return Err(Error::Diag(
+                target
+                    .loc()
+                    .position(st.sources)
+                    .diag_err("expected function, found expression".into())
+                    .build(),
+            ))
+        }
+    };
+    let fun = match st.funs.get(id.value.as_str()) {
+        Some(fun) => *fun,
+        None => {
+            return Err(Error::Diag(
+                id.loc
+                    .position(st.sources)
+                    .diag_err(format!("cannot find function `{}` in this scope", id.value))
+                    .build(),
+            ))
+        }
+    };
+    if fun.params.len() != call.args.len() {
+        return Err(Error::Diag(
+            call.loc
+                .position(st.sources)
+                .diag_err(format!(
+                    "this function takes {} argument{} but {} {} supplied",
+                    fun.params.len(),
+                    if fun.params.len() == 1 { "" } else { "s" },
+                    call.args.len(),
+                    if call.args.len() == 1 { "was" } else { "were" }
+                ))
+                .build(),
+        ));
+    }
+
+    let mut args = Vec::new();
+    for arg in &call.args {
+        args.push(transform_value(st, arg)?);
+    }
+    let tmp = st.temp();
+    st.block()
+        .push_op(ir::Op::call(symbol_name(fun), args, Some(tmp.into())));
+    Ok(tmp.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser, target};
+
+    /// The diagnostics lowering `source` fails with, without color codes
+    fn error(source: &str) -> String {
+        let mut sources = SourceMap::default();
+        let file = sources.load_string(source).unwrap();
+        let unit = parser::parse(sources.get(file)).unwrap();
+        colored::control::set_override(false);
+        match transform(&unit, &sources, target::host()) {
+            Ok(_) => panic!("expected {:?} not to compile", source),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn functions_defined_twice() {
+        let err = error("fn f() -> i64 { return 1; }\npub fn f() -> i64 { return 2; }\n");
+        assert_eq!(
+            err,
+            "<memory>:2:8: the name `f` is defined multiple times\n\
+             pub fn f() -> i64 { return 2; }\n\
+             \x20      ^\n\
+             <memory>:1:4: previous definition of `f` here\n\
+             fn f() -> i64 { return 1; }\n\
+             \x20  ^\n"
+        );
+    }
+
+    #[test]
+    fn pub_names_cannot_look_mangled() {
+        let err = error("pub fn _M1fE() -> i64 { return 1; }\nfn f() -> i64 { return 2; }\n");
+        assert!(err
+            .starts_with("<memory>:1:8: `pub` function `_M1fE` is named like a mangled symbol\n"));
+        assert!(err.ends_with("= note: it could clash with the symbol of a private `f`\n"));
+
+        // private ones get mangled again, so they can't clash
+        assert_eq!(
+            symbol_name(&parse_fn("fn _M1fE() -> i64 { return 1; }")),
+            "_M5_M1fEE"
+        );
+    }
+
+    #[test]
+    fn tails_are_checked_against_the_return_type() {
+        let err = error("fn f() { 1 }\n");
+        assert!(err.starts_with("<memory>:1:10: expected `()`, found `i64`\n"));
+
+        let err = error("fn g() {}\nfn f() -> i64 { g() }\n");
+        assert!(err.starts_with("<memory>:2:17: expected `i64`, found `()`\n"));
+
+        // calls to nothing have no type to check
+        let err = error("fn f() { return nope(); }\n");
+        assert!(err.contains("cannot find function `nope`"), "{}", err);
+        let err = error("fn f() { nope() }\n");
+        assert!(err.contains("cannot find function `nope`"), "{}", err);
+
+        // a tail in any other block would be thrown away
+        let err = error("fn f() -> i64 {\n    if 1 < 2 { 3 }\n    return 4;\n}\n");
+        assert!(err.starts_with("<memory>:2:16: expected `()`, found `i64`\n"));
+        assert!(
+            err.ends_with("= note: only a function's body has a value, add a `;` to discard it\n")
+        );
+    }
+
+    #[test]
+    fn break_and_continue_outside_of_loops() {
+        let err = error("fn f() {\n    if 1 < 2 { break; }\n}\n");
+        assert_eq!(
+            err,
+            "<memory>:2:16: `break` outside of a loop\n    if 1 < 2 { break; }\n               ^~~~~\n"
+        );
+
+        // loops in the caller don't count
+        let err = error("fn g() { continue; }\nfn f() { loop { g(); break; } }\n");
+        assert!(err.starts_with("<memory>:1:10: `continue` outside of a loop\n"));
+    }
+
+    #[test]
+    fn missing_returns_point_at_the_closing_brace() {
+        let err = error("fn g() -> i64 { let a = 1; }\n");
+        assert_eq!(
+            err,
+            "<memory>:1:28: expected `i64`, found `()`\n\
+             fn g() -> i64 { let a = 1; }\n\
+             \x20                          ^\n\
+             = note: the body can reach its end without returning a value\n"
+        );
+
+        // a loop only returns if it can't be broken out of
+        let err = error("fn g() -> i64 {\n    loop { break; }\n}\n");
+        assert!(err.starts_with("<memory>:3:1: expected `i64`, found `()`\n"));
+    }
+
+    #[test]
+    fn entry_points_are_pub() {
+        let err = error("fn _start() -> i64 { return 0; }\n");
+        assert!(err.starts_with("<memory>:1:4: entry point `_start` must be `pub`\n"));
+    }
+
+    fn parse_fn(source: &str) -> ast::FDecl {
+        let mut sources = SourceMap::default();
+        let file = sources.load_string(source).unwrap();
+        let unit = parser::parse(sources.get(file)).unwrap();
+        unit.funs.into_iter().next().unwrap()
+    }
+}
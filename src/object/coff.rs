@@ -0,0 +1,287 @@
+use super::Put;
+use crate::ir::encode::Code;
+use std::collections::HashMap;
+
+const FILE_HEADER_SIZE: u32 = 20;
+const SECTION_HEADER_SIZE: u32 = 40;
+const RELOC_SIZE: u32 = 10;
+
+const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+
+const IMAGE_SCN_CNT_CODE: u32 = 0x0000_0020;
+const IMAGE_SCN_ALIGN_16BYTES: u32 = 0x0050_0000;
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+
+const IMAGE_SYM_CLASS_EXTERNAL: u8 = 2;
+const IMAGE_SYM_CLASS_STATIC: u8 = 3;
+const IMAGE_SYM_DTYPE_FUNCTION: u16 = 0x20;
+const IMAGE_SYM_UNDEFINED: i16 = 0;
+
+const IMAGE_REL_AMD64_REL32: u16 = 4;
+
+/// 1-based, as section numbers are in symbols
+const TEXT: i16 = 1;
+
+/// Long symbol names, referenced by offset. Offsets count the 4-byte
+/// size field the table starts with.
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        Self { bytes: Vec::new() }
+    }
+
+    fn add(&mut self, s: &str) -> u32 {
+        let offset = 4 + self.bytes.len() as u32;
+        self.bytes.extend(s.as_bytes());
+        self.bytes.push(0);
+        offset
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.put_u32(4 + self.bytes.len() as u32);
+        out.extend(&self.bytes);
+    }
+}
+
+struct Sym {
+    name: [u8; 8],
+    value: u32,
+    section: i16,
+    typ: u16,
+    class: u8,
+    aux: Vec<[u8; 18]>,
+}
+
+impl Sym {
+    /// Names of up to 8 bytes are stored inline, longer ones go to the
+    /// string table
+    fn name(strtab: &mut StringTable, s: &str) -> [u8; 8] {
+        let mut name = [0u8; 8];
+        if s.len() <= 8 {
+            name[..s.len()].copy_from_slice(s.as_bytes());
+        } else {
+            name[4..].copy_from_slice(&strtab.add(s).to_le_bytes());
+        }
+        name
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend(&self.name);
+        out.put_u32(self.value);
+        out.put_u16(self.section as u16);
+        out.put_u16(self.typ);
+        out.put_u8(self.class);
+        out.put_u8(self.aux.len() as u8);
+        for aux in &self.aux {
+            out.extend(aux);
+        }
+    }
+
+    /// Symbol table slots taken, auxiliary records included
+    fn slots(&self) -> usize {
+        1 + self.aux.len()
+    }
+}
+
+/// Writes `code` as a COFF object (`.obj`) for x64 with a single
+/// `.text` section. Public functions become external symbols, private
+/// ones static, and calls to anything not in `code` are left undefined
+/// for the linker to resolve.
+///
+/// The timestamp is left at zero so output only depends on the input.
+pub fn write_object(code: &Code) -> Vec<u8> {
+    let mut strtab = StringTable::new();
+
+    // the section symbol's auxiliary record describes the section
+    let mut section_aux = Vec::new();
+    section_aux.put_u32(code.text.len() as u32);
+    section_aux.put_u16(code.relocs.len() as u16);
+    section_aux.put_u16(0); // line numbers
+    section_aux.put_u32(0); // checksum, only for COMDATs
+    section_aux.put_u16(0); // associated section, ditto
+    section_aux.put_u8(0); // COMDAT selection, ditto
+    section_aux.align_to(18);
+    let mut aux = [0u8; 18];
+    aux.copy_from_slice(&section_aux);
+
+    let mut syms = vec![Sym {
+        name: Sym::name(&mut strtab, ".text"),
+        value: 0,
+        section: TEXT,
+        typ: 0,
+        class: IMAGE_SYM_CLASS_STATIC,
+        aux: vec![aux],
+    }];
+    let mut sym_indices = HashMap::new();
+    let mut next_index = syms[0].slots();
+
+    for s in &code.symbols {
+        sym_indices.insert(s.name.as_str(), next_index);
+        let sym = Sym {
+            name: Sym::name(&mut strtab, &s.name),
+            value: s.offset as u32,
+            section: TEXT,
+            typ: IMAGE_SYM_DTYPE_FUNCTION,
+            class: if s.public {
+                IMAGE_SYM_CLASS_EXTERNAL
+            } else {
+                IMAGE_SYM_CLASS_STATIC
+            },
+            aux: vec![],
+        };
+        next_index += sym.slots();
+        syms.push(sym);
+    }
+    for r in &code.relocs {
+        if !sym_indices.contains_key(r.symbol.as_str()) {
+            sym_indices.insert(r.symbol.as_str(), next_index);
+            let sym = Sym {
+                name: Sym::name(&mut strtab, &r.symbol),
+                value: 0,
+                section: IMAGE_SYM_UNDEFINED,
+                typ: IMAGE_SYM_DTYPE_FUNCTION,
+                class: IMAGE_SYM_CLASS_EXTERNAL,
+                aux: vec![],
+            };
+            next_index += sym.slots();
+            syms.push(sym);
+        }
+    }
+
+    let text_offset = FILE_HEADER_SIZE + SECTION_HEADER_SIZE;
+    let relocs_offset = text_offset + code.text.len() as u32;
+    let symtab_offset = relocs_offset + code.relocs.len() as u32 * RELOC_SIZE;
+
+    let mut out = Vec::new();
+    out.put_u16(IMAGE_FILE_MACHINE_AMD64);
+    out.put_u16(1); // sections
+    out.put_u32(0); // timestamp
+    out.put_u32(symtab_offset);
+    out.put_u32(next_index as u32);
+    out.put_u16(0); // optional header size, only for images
+    out.put_u16(0); // characteristics
+
+    out.extend(b".text\0\0\0");
+    out.put_u32(0); // virtual size, only for images
+    out.put_u32(0); // virtual address, ditto
+    out.put_u32(code.text.len() as u32);
+    out.put_u32(text_offset);
+    out.put_u32(if code.relocs.is_empty() {
+        0
+    } else {
+        relocs_offset
+    });
+    out.put_u32(0); // line numbers
+    out.put_u16(code.relocs.len() as u16);
+    out.put_u16(0); // line numbers
+    out.put_u32(
+        IMAGE_SCN_CNT_CODE | IMAGE_SCN_ALIGN_16BYTES | IMAGE_SCN_MEM_EXECUTE | IMAGE_SCN_MEM_READ,
+    );
+
+    // REL32 is relative to the end of the 4-byte field, and its addend
+    // lives in the field itself
+    let mut text = code.text.clone();
+    for r in &code.relocs {
+        let addend = (r.addend + 4) as i32;
+        text[r.offset..r.offset + 4].copy_from_slice(&addend.to_le_bytes());
+    }
+    out.extend(&text);
+
+    for r in &code.relocs {
+        out.put_u32(r.offset as u32);
+        out.put_u32(sym_indices[r.symbol.as_str()] as u32);
+        out.put_u16(IMAGE_REL_AMD64_REL32);
+    }
+
+    for sym in &syms {
+        sym.write(&mut out);
+    }
+    strtab.write(&mut out);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::tests::{read, sample_code};
+
+    const SYMTAB_OFFSET: usize = 92;
+
+    fn sym(obj: &[u8], slot: usize, field: usize, size: usize) -> u64 {
+        read(obj, SYMTAB_OFFSET + slot * 18 + field, size)
+    }
+
+    #[test]
+    fn file_and_section_headers() {
+        let obj = write_object(&sample_code());
+        assert_eq!(read(&obj, 0, 2), 0x8664);
+        assert_eq!(read(&obj, 2, 2), 1);
+        assert_eq!(read(&obj, 4, 4), 0); // timestamp
+        assert_eq!(read(&obj, 8, 4), SYMTAB_OFFSET as u64);
+        assert_eq!(read(&obj, 12, 4), 5);
+        assert_eq!(read(&obj, 16, 2), 0);
+        assert_eq!(read(&obj, 18, 2), 0);
+
+        assert_eq!(obj[20..28], *b".text\0\0\0");
+        assert_eq!(read(&obj, 36, 4), 12); // raw data size
+        assert_eq!(read(&obj, 40, 4), 60); // raw data
+        assert_eq!(read(&obj, 44, 4), 72); // relocations
+        assert_eq!(read(&obj, 52, 2), 2);
+        assert_eq!(read(&obj, 56, 4), 0x6050_0020);
+    }
+
+    #[test]
+    fn relocations() {
+        let code = sample_code();
+        let obj = write_object(&code);
+        // the -4 addends cancel out against the end of the field
+        assert_eq!(obj[60..72], code.text[..]);
+
+        // IMAGE_REL_AMD64_REL32 against `f`, then against `ext`
+        assert_eq!(read(&obj, 72, 4), 2);
+        assert_eq!(read(&obj, 76, 4), 2);
+        assert_eq!(read(&obj, 80, 2), 4);
+        assert_eq!(read(&obj, 82, 4), 7);
+        assert_eq!(read(&obj, 86, 4), 4);
+        assert_eq!(read(&obj, 90, 2), 4);
+    }
+
+    #[test]
+    fn symbols() {
+        let obj = write_object(&sample_code());
+        let base = SYMTAB_OFFSET;
+
+        // `.text`, with an auxiliary record for the section
+        assert_eq!(obj[base..base + 8], *b".text\0\0\0");
+        assert_eq!(sym(&obj, 0, 12, 2), 1);
+        assert_eq!(sym(&obj, 0, 16, 1), 3);
+        assert_eq!(sym(&obj, 0, 17, 1), 1);
+        assert_eq!(sym(&obj, 1, 0, 4), 12);
+        assert_eq!(sym(&obj, 1, 4, 2), 2);
+
+        // (slot, value, section, type, class)
+        let expected = [(2, 0, 1, 0x20, 3), (3, 1, 1, 0x20, 2), (4, 0, 0, 0x20, 2)];
+        for &(slot, value, section, typ, class) in &expected {
+            assert_eq!(sym(&obj, slot, 8, 4), value, "slot {}", slot);
+            assert_eq!(sym(&obj, slot, 12, 2), section, "slot {}", slot);
+            assert_eq!(sym(&obj, slot, 14, 2), typ, "slot {}", slot);
+            assert_eq!(sym(&obj, slot, 16, 1), class, "slot {}", slot);
+            assert_eq!(sym(&obj, slot, 17, 1), 0, "slot {}", slot);
+        }
+
+        // short names inline, long ones by string table offset
+        let name = |slot: usize| &obj[base + slot * 18..base + slot * 18 + 8];
+        assert_eq!(name(2), b"f\0\0\0\0\0\0\0");
+        assert_eq!(name(3), [0, 0, 0, 0, 4, 0, 0, 0]);
+        assert_eq!(name(4), b"ext\0\0\0\0\0");
+
+        let strtab = base + 5 * 18;
+        assert_eq!(read(&obj, strtab, 4), 16);
+        assert_eq!(obj[strtab + 4..], *b"long_name_g\0");
+    }
+}
@@ -0,0 +1,534 @@
+use super::Put;
+use crate::ir::encode::Code;
+use std::collections::HashMap;
+
+const EHDR_SIZE: u16 = 64;
+const SHDR_SIZE: u16 = 64;
+const SYM_SIZE: u64 = 24;
+const RELA_SIZE: u64 = 24;
+
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 62;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const SHN_UNDEF: u16 = 0;
+
+const R_X86_64_PLT32: u32 = 4;
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_e551;
+const PF_X: u32 = 0x1;
+const PF_W: u32 = 0x2;
+const PF_R: u32 = 0x4;
+
+/// Where executables get mapped, same as GNU ld's default
+pub const EXEC_BASE: u64 = 0x40_0000;
+const PAGE_SIZE: u64 = 0x1000;
+/// Program headers in an executable: the code and the stack
+const EXEC_PHNUM: u16 = 2;
+/// Where `.text` starts in an executable, right after the headers
+pub const EXEC_TEXT_OFFSET: u64 = (EHDR_SIZE + EXEC_PHNUM * PHDR_SIZE) as u64;
+
+// Section indices, in the order `write_relocatable` lays them out
+const TEXT: u16 = 1;
+const RELA_TEXT: u16 = 2;
+const SYMTAB: u16 = 3;
+const STRTAB: u16 = 4;
+const SHSTRTAB: u16 = 5;
+const NOTE_GNU_STACK: u16 = 6;
+const SECTION_COUNT: u16 = 7;
+
+/// Null-terminated strings referenced by offset
+pub struct StringTable {
+    bytes: Vec<u8>,
+    offsets: HashMap<String, u32>,
+}
+
+impl StringTable {
+    pub fn new() -> Self {
+        // offset 0 is the empty string
+        Self {
+            bytes: vec![0],
+            offsets: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, s: &str) -> u32 {
+        if s.is_empty() {
+            return 0;
+        }
+        if let Some(&offset) = self.offsets.get(s) {
+            return offset;
+        }
+
+        let offset = self.bytes.len() as u32;
+        self.bytes.extend(s.as_bytes());
+        self.bytes.push(0);
+        self.offsets.insert(s.into(), offset);
+        offset
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Sym {
+    pub name: u32,
+    pub bind: u8,
+    pub typ: u8,
+    pub shndx: u16,
+    pub value: u64,
+    pub size: u64,
+}
+
+impl Sym {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.put_u32(self.name);
+        out.put_u8((self.bind << 4) | self.typ);
+        out.put_u8(0); // st_other: default visibility
+        out.put_u16(self.shndx);
+        out.put_u64(self.value);
+        out.put_u64(self.size);
+    }
+}
+
+#[derive(Default)]
+pub struct SectionHeader {
+    pub name: u32,
+    pub typ: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+    pub info: u32,
+    pub addralign: u64,
+    pub entsize: u64,
+}
+
+impl SectionHeader {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.put_u32(self.name);
+        out.put_u32(self.typ);
+        out.put_u64(self.flags);
+        out.put_u64(self.addr);
+        out.put_u64(self.offset);
+        out.put_u64(self.size);
+        out.put_u32(self.link);
+        out.put_u32(self.info);
+        out.put_u64(self.addralign);
+        out.put_u64(self.entsize);
+    }
+}
+
+pub struct Header {
+    pub typ: u16,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub phnum: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+const PHDR_SIZE: u16 = 56;
+
+impl Header {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend(b"\x7fELF");
+        out.put_u8(2); // 64-bit
+        out.put_u8(1); // little-endian
+        out.put_u8(1); // ELF version
+        out.put_u8(0); // System V ABI
+        out.extend(&[0; 8]);
+        out.put_u16(self.typ);
+        out.put_u16(EM_X86_64);
+        out.put_u32(1); // ELF version, again
+        out.put_u64(self.entry);
+        out.put_u64(self.phoff);
+        out.put_u64(self.shoff);
+        out.put_u32(0); // flags
+        out.put_u16(EHDR_SIZE);
+        out.put_u16(if self.phnum > 0 { PHDR_SIZE } else { 0 });
+        out.put_u16(self.phnum);
+        out.put_u16(if self.shnum > 0 { SHDR_SIZE } else { 0 });
+        out.put_u16(self.shnum);
+        out.put_u16(self.shstrndx);
+    }
+}
+
+pub struct ProgramHeader {
+    pub typ: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.put_u32(self.typ);
+        out.put_u32(self.flags);
+        out.put_u64(self.offset);
+        out.put_u64(self.vaddr);
+        out.put_u64(self.vaddr); // paddr, ignored
+        out.put_u64(self.filesz);
+        out.put_u64(self.memsz);
+        out.put_u64(self.align);
+    }
+}
+
+/// Writes a statically linked ELF64 executable. `text` must already
+/// be resolved for being loaded at `EXEC_BASE + EXEC_TEXT_OFFSET`.
+///
+/// The whole file is mapped as a single read+execute segment, headers
+/// included, which keeps offsets and addresses trivially in sync.
+pub fn write_executable(text: &[u8], entry: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    Header {
+        typ: ET_EXEC,
+        entry,
+        phoff: EHDR_SIZE as u64,
+        shoff: 0,
+        phnum: EXEC_PHNUM,
+        shnum: 0,
+        shstrndx: 0,
+    }
+    .write(&mut out);
+
+    let size = EXEC_TEXT_OFFSET + text.len() as u64;
+    ProgramHeader {
+        typ: PT_LOAD,
+        flags: PF_R | PF_X,
+        offset: 0,
+        vaddr: EXEC_BASE,
+        filesz: size,
+        memsz: size,
+        align: PAGE_SIZE,
+    }
+    .write(&mut out);
+    ProgramHeader {
+        typ: PT_GNU_STACK,
+        flags: PF_R | PF_W,
+        offset: 0,
+        vaddr: 0,
+        filesz: 0,
+        memsz: 0,
+        align: 16,
+    }
+    .write(&mut out);
+
+    debug_assert_eq!(out.len() as u64, EXEC_TEXT_OFFSET);
+    out.extend(text);
+    out
+}
+
+/// Writes `code` as an ELF64 relocatable object (`.o`) with a single
+/// `.text` section. Public functions become global symbols, private
+/// ones local, and calls to anything not in `code` are left undefined
+/// for the linker to resolve.
+pub fn write_relocatable(code: &Code) -> Vec<u8> {
+    let mut strtab = StringTable::new();
+    let mut syms = vec![
+        Sym {
+            name: 0,
+            bind: STB_LOCAL,
+            typ: STT_NOTYPE,
+            shndx: SHN_UNDEF,
+            value: 0,
+            size: 0,
+        },
+        Sym {
+            name: 0,
+            bind: STB_LOCAL,
+            typ: STT_SECTION,
+            shndx: TEXT,
+            value: 0,
+            size: 0,
+        },
+    ];
+    let mut sym_indices = HashMap::new();
+
+    // locals must come before globals in the symbol table
+    let (public, private): (Vec<_>, Vec<_>) = code.symbols.iter().partition(|s| s.public);
+    let first_global = syms.len() + private.len();
+    for (s, bind) in private
+        .iter()
+        .map(|s| (s, STB_LOCAL))
+        .chain(public.iter().map(|s| (s, STB_GLOBAL)))
+    {
+        sym_indices.insert(s.name.as_str(), syms.len());
+        syms.push(Sym {
+            name: strtab.add(&s.name),
+            bind,
+            typ: STT_FUNC,
+            shndx: TEXT,
+            value: s.offset as u64,
+            size: s.size as u64,
+        });
+    }
+    for r in &code.relocs {
+        if !sym_indices.contains_key(r.symbol.as_str()) {
+            sym_indices.insert(r.symbol.as_str(), syms.len());
+            syms.push(Sym {
+                name: strtab.add(&r.symbol),
+                bind: STB_GLOBAL,
+                typ: STT_NOTYPE,
+                shndx: SHN_UNDEF,
+                value: 0,
+                size: 0,
+            });
+        }
+    }
+
+    let mut shstrtab = StringTable::new();
+    let mut shdrs: Vec<SectionHeader> = (0..SECTION_COUNT).map(|_| Default::default()).collect();
+    // the header goes in last, once we know where everything is
+    let mut out = vec![0; EHDR_SIZE as usize];
+
+    out.align_to(16);
+    shdrs[TEXT as usize] = SectionHeader {
+        name: shstrtab.add(".text"),
+        typ: SHT_PROGBITS,
+        flags: SHF_ALLOC | SHF_EXECINSTR,
+        offset: out.len() as u64,
+        size: code.text.len() as u64,
+        addralign: 16,
+        ..Default::default()
+    };
+    out.extend(&code.text);
+
+    out.align_to(8);
+    let rela_offset = out.len();
+    for r in &code.relocs {
+        out.put_u64(r.offset as u64);
+        out.put_u64(((sym_indices[r.symbol.as_str()] as u64) << 32) | R_X86_64_PLT32 as u64);
+        out.put_u64(r.addend as u64);
+    }
+    shdrs[RELA_TEXT as usize] = SectionHeader {
+        name: shstrtab.add(".rela.text"),
+        typ: SHT_RELA,
+        flags: SHF_INFO_LINK,
+        offset: rela_offset as u64,
+        size: (out.len() - rela_offset) as u64,
+        link: SYMTAB as u32,
+        info: TEXT as u32,
+        addralign: 8,
+        entsize: RELA_SIZE,
+        ..Default::default()
+    };
+
+    let symtab_offset = out.len();
+    for sym in &syms {
+        sym.write(&mut out);
+    }
+    shdrs[SYMTAB as usize] = SectionHeader {
+        name: shstrtab.add(".symtab"),
+        typ: SHT_SYMTAB,
+        offset: symtab_offset as u64,
+        size: (out.len() - symtab_offset) as u64,
+        link: STRTAB as u32,
+        // one past the last local symbol
+        info: first_global as u32,
+        addralign: 8,
+        entsize: SYM_SIZE,
+        ..Default::default()
+    };
+
+    shdrs[STRTAB as usize] = SectionHeader {
+        name: shstrtab.add(".strtab"),
+        typ: SHT_STRTAB,
+        offset: out.len() as u64,
+        size: strtab.bytes().len() as u64,
+        addralign: 1,
+        ..Default::default()
+    };
+    out.extend(strtab.bytes());
+
+    // without it, linkers assume we need an executable stack
+    shdrs[NOTE_GNU_STACK as usize] = SectionHeader {
+        name: shstrtab.add(".note.GNU-stack"),
+        typ: SHT_PROGBITS,
+        offset: out.len() as u64,
+        addralign: 1,
+        ..Default::default()
+    };
+
+    let shstrtab_name = shstrtab.add(".shstrtab");
+    shdrs[SHSTRTAB as usize] = SectionHeader {
+        name: shstrtab_name,
+        typ: SHT_STRTAB,
+        offset: out.len() as u64,
+        size: shstrtab.bytes().len() as u64,
+        addralign: 1,
+        ..Default::default()
+    };
+    out.extend(shstrtab.bytes());
+
+    out.align_to(8);
+    let shoff = out.len() as u64;
+    for shdr in &shdrs {
+        shdr.write(&mut out);
+    }
+
+    let mut ehdr = Vec::new();
+    Header {
+        typ: ET_REL,
+        entry: 0,
+        phoff: 0,
+        shoff,
+        phnum: 0,
+        shnum: SECTION_COUNT,
+        shstrndx: SHSTRTAB,
+    }
+    .write(&mut ehdr);
+    out[..ehdr.len()].copy_from_slice(&ehdr);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::tests::{read, sample_code};
+
+    fn shdr(obj: &[u8], index: u16, field: usize, size: usize) -> u64 {
+        let shoff = read(obj, 40, 8) as usize;
+        read(
+            obj,
+            shoff + index as usize * SHDR_SIZE as usize + field,
+            size,
+        )
+    }
+
+    #[test]
+    fn relocatable_header() {
+        let obj = write_relocatable(&sample_code());
+        assert_eq!(obj[..16], *b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0");
+        assert_eq!(read(&obj, 16, 2), 1); // ET_REL
+        assert_eq!(read(&obj, 18, 2), 62); // EM_X86_64
+        assert_eq!(read(&obj, 20, 4), 1);
+        assert_eq!(read(&obj, 24, 8), 0); // no entry point
+        assert_eq!(read(&obj, 32, 8), 0); // no program headers
+        assert_eq!(read(&obj, 40, 8), 328);
+        assert_eq!(read(&obj, 52, 2), 64);
+        assert_eq!(read(&obj, 54, 2), 0);
+        assert_eq!(read(&obj, 58, 2), 64);
+        assert_eq!(read(&obj, 60, 2), 7);
+        assert_eq!(read(&obj, 62, 2), 5);
+        assert_eq!(obj.len(), 328 + 7 * 64);
+    }
+
+    #[test]
+    fn relocatable_sections() {
+        let code = sample_code();
+        let obj = write_relocatable(&code);
+        // (index, type, flags, offset, size, link, info, entsize)
+        let expected = [
+            (TEXT, 1, 0x6, 64, 12, 0, 0, 0),
+            (RELA_TEXT, 4, 0x40, 80, 48, 3, 1, 24),
+            (SYMTAB, 2, 0, 128, 120, 4, 3, 24),
+            (STRTAB, 3, 0, 248, 19, 0, 0, 0),
+            (SHSTRTAB, 3, 0, 267, 60, 0, 0, 0),
+            (NOTE_GNU_STACK, 1, 0, 267, 0, 0, 0, 0),
+        ];
+        for &(i, typ, flags, offset, size, link, info, entsize) in &expected {
+            assert_eq!(shdr(&obj, i, 4, 4), typ, "section {}", i);
+            assert_eq!(shdr(&obj, i, 8, 8), flags, "section {}", i);
+            assert_eq!(shdr(&obj, i, 24, 8), offset, "section {}", i);
+            assert_eq!(shdr(&obj, i, 32, 8), size, "section {}", i);
+            assert_eq!(shdr(&obj, i, 40, 4), link, "section {}", i);
+            assert_eq!(shdr(&obj, i, 44, 4), info, "section {}", i);
+            assert_eq!(shdr(&obj, i, 56, 8), entsize, "section {}", i);
+        }
+        assert_eq!(obj[64..76], code.text[..]);
+        assert_eq!(obj[248..267], *b"\0f\0long_name_g\0ext\0");
+    }
+
+    #[test]
+    fn relocatable_symbols() {
+        let obj = write_relocatable(&sample_code());
+        let sym = |i: usize, field: usize, size: usize| read(&obj, 128 + i * 24 + field, size);
+
+        // (name, info, shndx, value, size): the null symbol, `.text`,
+        // then locals before globals
+        let expected = [
+            (0, 0x00, 0, 0, 0),
+            (0, 0x03, 1, 0, 0),
+            (1, 0x02, 1, 0, 1),
+            (3, 0x12, 1, 1, 11),
+            (15, 0x10, 0, 0, 0),
+        ];
+        for (i, &(name, info, shndx, value, size)) in expected.iter().enumerate() {
+            assert_eq!(sym(i, 0, 4), name, "symbol {}", i);
+            assert_eq!(sym(i, 4, 1), info, "symbol {}", i);
+            assert_eq!(sym(i, 5, 1), 0, "symbol {}", i);
+            assert_eq!(sym(i, 6, 2), shndx, "symbol {}", i);
+            assert_eq!(sym(i, 8, 8), value, "symbol {}", i);
+            assert_eq!(sym(i, 16, 8), size, "symbol {}", i);
+        }
+    }
+
+    #[test]
+    fn relocatable_relocations() {
+        let obj = write_relocatable(&sample_code());
+        // R_X86_64_PLT32 against `f`, then against `ext`
+        assert_eq!(read(&obj, 80, 8), 2);
+        assert_eq!(read(&obj, 88, 8), (2 << 32) | 4);
+        assert_eq!(read(&obj, 96, 8) as i64, -4);
+        assert_eq!(read(&obj, 104, 8), 7);
+        assert_eq!(read(&obj, 112, 8), (4 << 32) | 4);
+        assert_eq!(read(&obj, 120, 8) as i64, -4);
+    }
+
+    #[test]
+    fn executable_headers() {
+        let exe = write_executable(&[0xC3], EXEC_BASE + EXEC_TEXT_OFFSET);
+        assert_eq!(exe[..4], *b"\x7fELF");
+        assert_eq!(read(&exe, 16, 2), 2); // ET_EXEC
+        assert_eq!(read(&exe, 24, 8), 0x40_00b0);
+        assert_eq!(read(&exe, 32, 8), 64);
+        assert_eq!(read(&exe, 40, 8), 0);
+        assert_eq!(read(&exe, 54, 2), 56);
+        assert_eq!(read(&exe, 56, 2), 2);
+        assert_eq!(read(&exe, 60, 2), 0);
+
+        // PT_LOAD, r-x, the whole file at EXEC_BASE
+        assert_eq!(read(&exe, 64, 4), 1);
+        assert_eq!(read(&exe, 68, 4), 5);
+        assert_eq!(read(&exe, 72, 8), 0);
+        assert_eq!(read(&exe, 80, 8), 0x40_0000);
+        assert_eq!(read(&exe, 96, 8), 0xb1);
+        assert_eq!(read(&exe, 104, 8), 0xb1);
+        assert_eq!(read(&exe, 112, 8), 0x1000);
+        // PT_GNU_STACK, rw-
+        assert_eq!(read(&exe, 120, 4), 0x6474_e551);
+        assert_eq!(read(&exe, 124, 4), 6);
+
+        assert_eq!(exe.len(), 0xb1);
+        assert_eq!(exe[0xb0], 0xC3);
+    }
+}
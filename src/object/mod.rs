@@ -0,0 +1,85 @@
+pub mod coff;
+pub mod elf;
+
+/// Little-endian appends, for laying out object file structures by hand
+pub trait Put {
+    fn put_u8(&mut self, v: u8);
+    fn put_u16(&mut self, v: u16);
+    fn put_u32(&mut self, v: u32);
+    fn put_u64(&mut self, v: u64);
+
+    /// Zero-pads until the length is a multiple of `align`
+    fn align_to(&mut self, align: usize);
+}
+
+impl Put for Vec<u8> {
+    fn put_u8(&mut self, v: u8) {
+        self.push(v)
+    }
+
+    fn put_u16(&mut self, v: u16) {
+        self.extend(&v.to_le_bytes())
+    }
+
+    fn put_u32(&mut self, v: u32) {
+        self.extend(&v.to_le_bytes())
+    }
+
+    fn put_u64(&mut self, v: u64) {
+        self.extend(&v.to_le_bytes())
+    }
+
+    fn align_to(&mut self, align: usize) {
+        while !self.len().is_multiple_of(align) {
+            self.push(0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::encode::{Code, Reloc, Symbol};
+
+    /// Reads a little-endian field of `size` bytes
+    pub fn read(bytes: &[u8], offset: usize, size: usize) -> u64 {
+        let mut v = [0u8; 8];
+        v[..size].copy_from_slice(&bytes[offset..offset + size]);
+        u64::from_le_bytes(v)
+    }
+
+    /// A private function `f`, then a public `long_name_g` calling `f`
+    /// and the undefined `ext`
+    pub fn sample_code() -> Code {
+        let mut text = vec![0xC3];
+        text.extend(&[0xE8, 0, 0, 0, 0, 0xE8, 0, 0, 0, 0, 0xC3]);
+        Code {
+            text,
+            symbols: vec![
+                Symbol {
+                    name: "f".into(),
+                    public: false,
+                    offset: 0,
+                    size: 1,
+                },
+                Symbol {
+                    name: "long_name_g".into(),
+                    public: true,
+                    offset: 1,
+                    size: 11,
+                },
+            ],
+            relocs: vec![
+                Reloc {
+                    offset: 2,
+                    symbol: "f".into(),
+                    addend: -4,
+                },
+                Reloc {
+                    offset: 7,
+                    symbol: "ext".into(),
+                    addend: -4,
+                },
+            ],
+        }
+    }
+}
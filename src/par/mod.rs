@@ -0,0 +1,123 @@
+use std::thread;
+
+/// Calls `f` on every item, spread over as many threads as there are
+/// cores. Results come back in the same order as `items`, so the output
+/// doesn't depend on how the work got scheduled.
+pub fn map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let threads =
+        forced_threads().unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()));
+    if threads < 2 || items.len() < 2 {
+        return items.iter().map(f).collect();
+    }
+
+    let chunk_size = items.len().div_ceil(threads);
+    let f = &f;
+    thread::scope(|s| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| s.spawn(move || chunk.iter().map(f).collect::<Vec<_>>()))
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+/// Like `map`, but stops at the first error, in `items` order
+pub fn try_map<T, R, E, F>(items: &[T], f: F) -> Result<Vec<R>, E>
+where
+    T: Sync,
+    R: Send,
+    E: Send,
+    F: Fn(&T) -> Result<R, E> + Sync,
+{
+    map(items, f).into_iter().collect()
+}
+
+#[cfg(not(test))]
+fn forced_threads() -> Option<usize> {
+    None
+}
+
+#[cfg(test)]
+fn forced_threads() -> Option<usize> {
+    tests::THREADS.with(|t| t.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ir, middle, object, parser, target};
+    use std::cell::Cell;
+
+    thread_local! {
+        /// Overrides the number of threads `map` uses, for calls made on
+        /// this thread
+        pub static THREADS: Cell<Option<usize>> = const { Cell::new(None) };
+    }
+
+    fn with_threads<R>(threads: usize, f: impl FnOnce() -> R) -> R {
+        THREADS.with(|t| t.set(Some(threads)));
+        let res = f();
+        THREADS.with(|t| t.set(None));
+        res
+    }
+
+    /// Assembly and object file for `source`, for every target
+    fn compile(source: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut sources = parser::SourceMap::default();
+        let file = sources.load_string(source).unwrap();
+        let unit = parser::parse(sources.get(file)).unwrap();
+
+        target::ALL
+            .iter()
+            .map(|&target| {
+                let funcs = middle::transform(&unit, &sources, target).unwrap();
+                let v: Vec<_> = funcs.iter().collect();
+
+                let mut asm = Vec::new();
+                ir::emit::emit_all(&mut asm, &v, target).unwrap();
+                let code = ir::encode::encode_all(&v, target).unwrap();
+                let obj = match target.object_format() {
+                    target::ObjectFormat::Elf64 => object::elf::write_relocatable(&code),
+                    target::ObjectFormat::Win64 => object::coff::write_object(&code),
+                };
+                (asm, obj)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn output_does_not_depend_on_threads() {
+        let mut source = String::new();
+        for n in 0..24 {
+            source.push_str(&format!(
+                "fn f{n}(a: i64) -> i64 {{
+                     let i = 0;
+                     loop {{ if i > a {{ break; }} i += {n} + 1; }}
+                     if i == a {{ return f{next}(i); }}
+                     return i;
+                 }}\n",
+                n = n,
+                next = (n + 1) % 24
+            ));
+        }
+        source.push_str("pub fn _start() -> i64 { return f0(10); }\n");
+
+        let sequential = with_threads(1, || compile(&source));
+        for &threads in &[2, 3, 8] {
+            let parallel = with_threads(threads, || compile(&source));
+            assert!(
+                parallel == sequential,
+                "output differs on {} threads",
+                threads
+            );
+        }
+    }
+}
@@ -1,21 +1,20 @@
 use colored::*;
 use nom::{
-    error::{VerboseError, VerboseErrorKind},
-    Err,
+    error::{ErrorKind, ParseError},
+    Err, InputLength,
 };
+use std::cell::RefCell;
 use std::fmt;
-use std::iter::repeat;
-use std::path::Path;
-use std::rc::Rc;
 
-use crate::{ast, parser};
-use parser::Span;
+use crate::{ast, lexer, parser};
+use parser::{Source, Span};
 
 /// A parsing, checking, or emitting error
 pub enum Error {
     IO(std::io::Error),
-    Source(SourceError),
     Diag(parser::Diagnostic),
+    /// Several errors from the same pass, in source order
+    Diags(Vec<parser::Diagnostic>),
     Unknown(UnknownError),
 }
 
@@ -23,8 +22,17 @@ impl<'a> fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::IO(e) => write!(f, "{}", e),
-            Error::Source(e) => write!(f, "{:#?}", e),
             Error::Diag(d) => write!(f, "{}", d),
+            Error::Diags(ds) => {
+                writeln!(f)?;
+                for d in ds {
+                    writeln!(f, "{}", d)?;
+                }
+                match ds.len() {
+                    1 => write!(f, "aborting due to previous error"),
+                    n => write!(f, "aborting due to {} previous errors", n),
+                }
+            }
             Error::Unknown(_) => write!(f, "unknown error"),
         }
     }
@@ -38,27 +46,13 @@ impl<'a> fmt::Debug for Error {
 
 impl std::error::Error for Error {}
 
-pub struct SourceError {
-    inner: VerboseError<parser::Span>,
-}
-
-impl<'a> fmt::Debug for SourceError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        print_errors(f, &self.inner)
-    }
-}
-
 pub struct UnknownError {
-    source: Rc<Source>,
+    file: String,
 }
 
 impl fmt::Debug for UnknownError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "An unknown parsing error occured in {}",
-            self.source.name()
-        )
+        write!(f, "An unknown parsing error occured in {}", self.file)
     }
 }
 
@@ -74,94 +68,118 @@ impl From<parser::Diagnostic> for Error {
     }
 }
 
-/// A `.mor` source file
-pub struct Source {
-    pub input: String,
-    name: String,
-    pub lines: Vec<String>,
-}
-
-impl Source {
-    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Rc<Self>, std::io::Error> {
-        let path = path.as_ref();
+/// Columns a tab advances to the next multiple of, in diagnostics
+const TAB_WIDTH: usize = 4;
 
-        let mut input = String::new();
-        {
-            use std::fs::File;
-            use std::io::Read;
-            let mut f = File::open(path)?;
-            f.read_to_string(&mut input)?;
-        }
-
-        let name = path.to_str().unwrap();
-        Ok(Self::new(name.into(), input))
-    }
-
-    #[allow(unused)]
-    pub fn from_string<S>(input: S) -> Rc<Self>
-    where
-        S: Into<String>,
-    {
-        Self::new("<memory>".into(), input.into())
-    }
-
-    pub fn new(name: String, input: String) -> Rc<Self> {
-        let lines = input.lines().map(String::from).collect::<Vec<_>>();
-        Rc::new(Self { name, input, lines })
-    }
-
-    pub fn name(&self) -> &str {
-        self.name.as_ref()
-    }
-}
-
-fn do_parse<P, O>(source: Rc<Source>, p: P) -> Result<O, Error>
+fn do_parse<P, O>(source: &Source, p: P) -> Result<O, Error>
 where
-    P: Fn(Span) -> nom::IResult<Span, O, VerboseError<parser::Span>>,
+    P: Fn(parser::Tokens) -> parser::Res<O>,
 {
-    let span = parser::Span {
-        source: source.clone(),
-        offset: 0,
-        len: source.input.len(),
+    let tokens = lexer::lex(source)?;
+    let errors = RefCell::new(Vec::new());
+
+    let output = {
+        let input = parser::Tokens::new(&tokens, source, &errors);
+        match p(input) {
+            Ok((_, output)) => Some(output),
+            Err(Err::Error(e)) | Err(Err::Failure(e)) => {
+                input.report(e);
+                None
+            }
+            Err(_) => {
+                let file = source.name().into();
+                return Err(Error::Unknown(UnknownError { file }));
+            }
+        }
     };
-    let res = p(span);
-    match res {
-        Err(Err::Error(e)) | Err(Err::Failure(e)) => Err(Error::Source(SourceError { inner: e })),
-        Err(_) => Err(Error::Unknown(UnknownError {
-            source: source.clone(),
-        })),
-        Ok((_, output)) => Ok(output),
+
+    let errors = errors.into_inner();
+    match output {
+        Some(output) if errors.is_empty() => Ok(output),
+        _ => Err(Error::Diags(
+            errors.iter().map(|e| syntax_error(source, e)).collect(),
+        )),
     }
 }
 
-pub fn parse(source: Rc<Source>) -> Result<ast::Unit, Error> {
+pub fn parse(source: &Source) -> Result<ast::Unit, Error> {
     do_parse(source, parser::unit)
 }
 
+pub fn parse_repl_line(source: &Source) -> Result<ast::ReplLine, Error> {
+    do_parse(source, parser::repl_line)
+}
+
+/// A rendered error or note. Owns the bit of source it shows, so it
+/// outlives the `SourceMap` it came from.
 #[derive(Debug)]
 pub struct Diagnostic {
-    pos: Position,
+    excerpt: Box<Excerpt>,
     caret_color: Color,
     prefix: String,
     message: String,
+    notes: Vec<String>,
+    related: Vec<Diagnostic>,
 }
 
 pub struct DiagnosticBuilder {
-    pos: Position,
+    excerpt: Box<Excerpt>,
     caret_color: Color,
     prefix: String,
     message: Option<String>,
+    notes: Vec<String>,
+    related: Vec<Diagnostic>,
+}
+
+/// The line a diagnostic points at, and which columns of it to underline
+#[derive(Debug)]
+struct Excerpt {
+    name: String,
+    /// Zero-based
+    line: usize,
+    /// Zero-based, in chars
+    column: usize,
+    /// With tabs expanded
+    text: String,
+    /// Display columns of the first and one past the last underlined char
+    carets: (usize, usize),
+}
+
+impl Excerpt {
+    fn new(pos: &Position) -> Self {
+        let source = pos.source;
+
+        // the underline stops at the end of the line, for multi-line spans
+        let text_line = source.line(pos.line);
+        let offset = source.offset(pos.span);
+        let (_, line_start) = source.line_of(offset);
+        let start = std::cmp::min(offset - line_start, text_line.len());
+        let end = std::cmp::min(start + pos.span.len(), text_line.len());
+
+        Self {
+            name: source.name().into(),
+            line: pos.line,
+            column: pos.column,
+            text: expand_tabs(text_line),
+            carets: (
+                display_column(text_line, start),
+                display_column(text_line, end),
+            ),
+        }
+    }
 }
 
 const EMPTY_PREFIX: &str = "";
 
 impl DiagnosticBuilder {
-    pub fn new(pos: Position) -> Self {
+    pub fn new(pos: &Position) -> Self {
         Self {
-            pos: pos.clone(),
+            excerpt: Box::new(Excerpt::new(pos)),
             caret_color: Color::Blue,
             prefix: EMPTY_PREFIX.into(),
             message: None,
+            notes: Vec::new(),
+            related: Vec::new(),
         }
     }
 
@@ -180,12 +198,26 @@ impl DiagnosticBuilder {
         self
     }
 
+    /// Secondary information, shown under the caret
+    pub fn note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    /// Another place the diagnostic is about, shown after it
+    pub fn related(mut self, related: Diagnostic) -> Self {
+        self.related.push(related);
+        self
+    }
+
     pub fn build(self) -> Diagnostic {
         Diagnostic {
-            pos: self.pos,
+            excerpt: self.excerpt,
             caret_color: self.caret_color,
             prefix: self.prefix,
             message: self.message.unwrap_or_else(|| "".into()),
+            notes: self.notes,
+            related: self.related,
         }
     }
 }
@@ -212,71 +244,78 @@ impl DiagnosticBuilder {
 
 impl fmt::Display for Diagnostic {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut pos = self.pos.clone();
-        if self.pos.span.slice().starts_with("}") {
-            let span = &self.pos.span;
-            let haystack = &span.source.input[0..span.offset];
-
-            let wsp = " \t\r\n";
-            if let Some(index) = haystack.rfind(|c| !wsp.contains(c)) {
-                let index = index + 1;
-                let new_slice = &haystack[index..];
-
-                let span = Span {
-                    source: span.source.clone(),
-                    offset: index,
-                    len: 1,
-                };
-                pos = span.position();
-            }
-        }
-
         let caret_color = self.caret_color;
         let prefix = &self.prefix;
         let message = &self.message;
 
-        let text_line = &pos.span.source.lines[pos.line];
+        let excerpt = &self.excerpt;
         let loc = format!(
             "{}:{}:{}:",
-            pos.span.source.name(),
-            pos.line + 1,
-            pos.column + 1
+            excerpt.name,
+            excerpt.line + 1,
+            excerpt.column + 1
         );
         writeln!(f, "{}{} {}", prefix, loc.bold(), message)?;
-        writeln!(f, "{}{}", prefix, text_line.dimmed())?;
+        writeln!(f, "{}{}", prefix, excerpt.text.dimmed())?;
+
+        let (start_col, end_col) = excerpt.carets;
 
         writeln!(
             f,
             "{}{}{}{}",
             prefix,
-            repeat(' ').take(pos.column).collect::<String>(),
+            " ".repeat(start_col),
             "^".color(caret_color).bold(),
-            repeat('~')
-                .take(std::cmp::min(
-                    match pos.span.len {
-                        0 => 0,
-                        x => x - 1,
-                    },
-                    text_line.len() - pos.column
-                ))
-                .collect::<String>()
+            "~".repeat(end_col.saturating_sub(start_col + 1))
                 .color(caret_color)
                 .bold()
         )?;
+        for note in &self.notes {
+            writeln!(f, "{}{} {}", prefix, "= note:".bold(), note)?;
+        }
+        for related in &self.related {
+            write!(f, "{}", related)?;
+        }
         Ok(())
     }
 }
 
-#[derive(Clone)]
-pub struct Position {
+/// Where byte `index` of `line` ends up once tabs are expanded
+fn display_column(line: &str, index: usize) -> usize {
+    line[..index].chars().fold(0, |col, c| match c {
+        '\t' => (col / TAB_WIDTH + 1) * TAB_WIDTH,
+        _ => col + 1,
+    })
+}
+
+fn expand_tabs(line: &str) -> String {
+    let mut out = String::new();
+    for c in line.chars() {
+        match c {
+            '\t' => {
+                let col = out.chars().count();
+                let next = (col / TAB_WIDTH + 1) * TAB_WIDTH;
+                out.push_str(&" ".repeat(next - col));
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[derive(Clone, Copy)]
+pub struct Position<'a> {
     pub span: Span,
+    /// Zero-based
     pub line: usize,
+    /// Zero-based, in chars
     pub column: usize,
+    pub source: &'a Source,
 }
 
-impl Position {
+impl<'a> Position<'a> {
     fn diag(&self, message: String) -> DiagnosticBuilder {
-        DiagnosticBuilder::new(self.clone()).message(message)
+        DiagnosticBuilder::new(self).message(message)
     }
 
     pub fn diag_info(&self, message: String) -> DiagnosticBuilder {
@@ -288,41 +327,202 @@ impl Position {
     }
 }
 
-impl fmt::Debug for Position {
+impl<'a> fmt::Debug for Position<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}:{}:{}", self.span.source.name, self.line, self.column)
+        write!(f, "{}:{}:{}", self.source.name(), self.line, self.column)
     }
 }
 
-pub fn print_errors(f: &mut fmt::Formatter, e: &VerboseError<Span>) -> fmt::Result {
-    let mut errors = e.errors.clone();
-    errors.reverse();
-
-    writeln!(f)?;
-    for (span, kind) in errors.iter() {
-        let pos = span.position();
-
-        match kind {
-            VerboseErrorKind::Char(c) => {
-                pos.diag_err(format!(
-                    "expected '{}', found {}",
-                    c,
-                    span.chars().next().unwrap_or_else(|| '\0')
-                ))
-                .write(f)?;
-            }
-            VerboseErrorKind::Context(s) => {
-                pos.diag_info(format!("In {}", s)).write(f)?;
-            }
-            VerboseErrorKind::Nom(ek) => {
-                pos.diag_err(format!(
-                    "parsing error: {}",
-                    format!("{:#?}", ek).red().bold()
-                ))
-                .write(f)?;
+/// Why parsing failed: the token found instead of what was expected
+/// there, and the constructs being parsed at the time
+#[derive(Debug, Clone)]
+pub struct SyntaxError<I> {
+    pub at: I,
+    /// Descriptions, like "`;`" or "expression"
+    pub expected: Vec<&'static str>,
+    /// Innermost first, like "let binding"
+    pub context: Vec<&'static str>,
+}
+
+impl<I> SyntaxError<I> {
+    pub fn expected(at: I, what: &'static str) -> Self {
+        Self {
+            at,
+            expected: vec![what],
+            context: Vec::new(),
+        }
+    }
+}
+
+impl<'a> ParseError<parser::Tokens<'a>> for SyntaxError<parser::Tokens<'a>> {
+    fn from_error_kind(at: parser::Tokens<'a>, _: ErrorKind) -> Self {
+        Self {
+            at,
+            expected: Vec::new(),
+            context: Vec::new(),
+        }
+    }
+
+    fn append(_: parser::Tokens<'a>, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    /// Keeps whichever alternative got further, or everything they
+    /// expected if they failed at the same token
+    fn or(mut self, other: Self) -> Self {
+        use std::cmp::Ordering;
+
+        match self.at.input_len().cmp(&other.at.input_len()) {
+            Ordering::Less => self,
+            Ordering::Greater => other,
+            Ordering::Equal => {
+                for what in other.expected {
+                    if !self.expected.contains(&what) {
+                        self.expected.push(what);
+                    }
+                }
+                self
             }
         }
     }
 
-    Ok(())
+    fn add_context(_: parser::Tokens<'a>, context: &'static str, mut other: Self) -> Self {
+        other.context.push(context);
+        other
+    }
+}
+
+/// "expected `;` after expression, found `}`", with a note naming the
+/// innermost construct being parsed
+fn syntax_error(source: &Source, e: &SyntaxError<Span>) -> Diagnostic {
+    let found = match e.at.len() {
+        0 => "end of file".to_string(),
+        _ => format!("`{}`", source.text(e.at)),
+    };
+
+    let message = match e.expected.as_slice() {
+        [] => format!("unexpected {}", found),
+        [what] => format!("expected {}, found {}", what, found),
+        [init @ .., last] => format!(
+            "expected one of {}, or {}, found {}",
+            init.join(", "),
+            last,
+            found
+        ),
+    };
+
+    // something missing before a `}` is blamed on whatever precedes it,
+    // which is usually where it was forgotten
+    let mut at = e.at;
+    if source.text(at).starts_with('}') {
+        let haystack = &source.input[0..source.offset(at)];
+
+        let wsp = " \t\r\n";
+        let last = haystack
+            .char_indices()
+            .rev()
+            .find(|&(_, c)| !wsp.contains(c));
+        if let Some((index, c)) = last {
+            at = source.span(index + c.len_utf8(), 1);
+        }
+    }
+
+    let mut diag = source.position(at).diag_err(message);
+    if let Some(context) = e.context.first() {
+        diag = diag.note(format!("in {}", context));
+    }
+    diag.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostics(source: &str) -> Vec<Diagnostic> {
+        let mut sources = parser::SourceMap::default();
+        let file = sources.load_string(source).unwrap();
+        match parse(sources.get(file)) {
+            Err(Error::Diags(ds)) => ds,
+            res => panic!("expected syntax errors, got {:?}", res.map(|_| ())),
+        }
+    }
+
+    /// Display without color codes
+    fn rendered(d: &Diagnostic) -> String {
+        colored::control::set_override(false);
+        d.to_string()
+    }
+
+    /// (line, column, message), one-based like in the output
+    fn located(d: &Diagnostic) -> (usize, usize, &str) {
+        let excerpt = &d.excerpt;
+        (excerpt.line + 1, excerpt.column + 1, d.message.as_str())
+    }
+
+    #[test]
+    fn missing_operands_are_reported_where_they_are_missing() {
+        let ds = diagnostics(
+            "fn f() -> i64 {\n    return 1 +;\n}\nfn g() -> i64 {\n    return -;\n}\n\
+             fn h() -> i64 {\n    return g(1 +);\n}\n",
+        );
+        let found: Vec<_> = ds.iter().map(located).collect();
+        assert_eq!(
+            found,
+            [
+                (2, 15, "expected expression, found `;`"),
+                (5, 13, "expected expression, found `;`"),
+                (8, 17, "expected expression, found `)`"),
+            ]
+        );
+    }
+    #[test]
+    fn carets_after_non_ascii_text() {
+        // a `}` is blamed on the end of whatever precedes it
+        let ds = diagnostics("pub fn _start() -> i64 {\n let x = 1 // café\n}");
+        assert_eq!(
+            rendered(&ds[0]),
+            "<memory>:2:19: expected `;` after let binding, found `}`\n \
+             let x = 1 // café\n\
+             \x20                 ^\n"
+        );
+
+        // four bytes, then trailing whitespace
+        let ds = diagnostics("pub fn _start() -> i64 {\n    return 1 // 🦀 \t\n}");
+        assert!(rendered(&ds[0]).starts_with("<memory>:2:18: expected `;`"));
+    }
+
+    /// Each level used to be parsed twice, as an expression then as a
+    /// statement, which took seconds by twenty levels
+    #[test]
+    fn deeply_nested_blocks() {
+        let depth = 64;
+        let source = format!(
+            "pub fn _start() -> i64 {{ {} let a = 1; {} return 0; }}",
+            "{ ".repeat(depth),
+            "} ".repeat(depth)
+        );
+        let mut sources = parser::SourceMap::default();
+        let file = sources.load_string(source).unwrap();
+        let unit = parse(sources.get(file)).unwrap();
+
+        let mut block = &unit.funs[0].body;
+        for _ in 0..depth {
+            block = match &block.items[0] {
+                ast::Statement::Block(inner) => inner,
+                stat => panic!("expected a block, got {:?}", stat),
+            };
+        }
+        assert!(matches!(block.items[..], [ast::Statement::VDecl(_)]));
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let ds = diagnostics("pub fn _start() -> i64 {\r\n\tlet x = 1\r\n\treturn x;\r\n}\r\n");
+        assert_eq!(
+            rendered(&ds[0]),
+            "<memory>:3:2: expected `;` after let binding, found `return`\n    \
+             return x;\n    \
+             ^~~~~~\n"
+        );
+    }
 }
@@ -2,177 +2,377 @@
 
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until, take_while, take_while1},
-    character::complete::char,
-    combinator::{all_consuming, cut, map, map_res, opt, recognize},
-    error::{context, ParseError, VerboseError},
-    multi::{many0, many1, separated_list},
+    combinator::{cut, map, map_res, opt},
+    error::{context, ErrorKind, ParseError},
+    multi::{many0, separated_list},
     sequence::{delimited, preceded, separated_pair, terminated, tuple},
-    IResult, InputTake,
+    IResult,
 };
 
 mod errors;
+mod source_map;
 mod span;
+mod tokens;
 
 use super::ast::*;
+use crate::lexer::{Token, TokenKind as T};
 pub use errors::*;
+pub use source_map::*;
 pub use span::*;
+pub use tokens::*;
 
-pub type Res<T> = IResult<Span, T, VerboseError<Span>>;
+pub type Res<'a, T> = IResult<Tokens<'a>, T, SyntaxError<Tokens<'a>>>;
 
-pub fn unit(i: Span) -> Res<Unit> {
-    all_consuming(terminated(
-        map(many0(unit_item), move |items| Unit::new(items)),
-        // FIXME: that looks big dumb, but it eats up
-        // trailing whitespace so all_consuming is happy..
-        spaced(tag("")),
-    ))(i)
+/// Parses a whole file. Items that fail to parse are reported and
+/// skipped, so this only fails on errors nom can't recover from.
+pub fn unit(i: Tokens) -> Res<Unit> {
+    let mut i = i;
+    let mut items = Vec::new();
+
+    while i.first().kind != T::Eof {
+        match unit_item(i) {
+            Ok((i2, item)) => {
+                items.push(item);
+                i = i2;
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                i.report(e);
+                let (i2, loc) = skip_item(i);
+                items.push(UnitItem::Error(loc));
+                i = i2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok((i, Unit::new(items)))
+}
+
+/// One line of REPL input: a declaration, a statement, or a bare
+/// expression whose value should be shown
+pub fn repl_line(i: Tokens) -> Res<ReplLine> {
+    terminated(
+        alt((
+            map(fn_decl, ReplLine::FDecl),
+            map(terminated(expression, eof), ReplLine::Expr),
+            map(statement, ReplLine::Statement),
+        )),
+        eof,
+    )(i)
 }
 
-fn unit_item(i: Span) -> Res<UnitItem> {
-    map(fn_decl, |f| UnitItem::FDecl(f))(i)
+fn unit_item(i: Tokens) -> Res<UnitItem> {
+    map(fn_decl, UnitItem::FDecl)(i)
 }
 
-fn fn_decl(i: Span) -> Res<FDecl> {
-    spaced(context("fn declaration", |i| {
-        let (i, public) = opt(stag("pub"))(i)?;
-        let (i, _) = stag("fn")(i)?;
+fn fn_decl(i: Tokens) -> Res<FDecl> {
+    let start = i;
+    context("fn declaration", move |i| {
+        let (i, public) = opt(tok(T::Pub))(i)?;
+        let (i, _) = tok(T::Fn)(i)?;
         cut(move |i| {
-            let (i, name) = spaced(identifier)(i)?;
+            let (i, name) = identifier(i)?;
             let (i, params) = param_list(i)?;
-            let (i, body) = spaced(block)(i)?;
+            let (i, ret) = opt(preceded(tok(T::Arrow), cut(type_reference)))(i)?;
+            let (i, body) = block(i)?;
 
             let fun = FDecl {
+                loc: start.span_to(i),
                 body,
                 params,
+                ret,
                 name,
                 public: public.is_some(),
             };
-            return Ok((i, fun));
+            Ok((i, fun))
         })(i)
-    }))(i)
+    })(i)
 }
 
-fn param_list(i: Span) -> Res<Vec<Param>> {
-    spaced(context("param list", |i| {
+fn param_list(i: Tokens) -> Res<Vec<Param>> {
+    context("param list", |i| {
         delimited(
-            tag("("),
-            cut(separated_list(tag(","), spaced(parameter))),
-            tag(")"),
+            tok(T::LParen),
+            cut(separated_list(tok(T::Comma), parameter)),
+            tok(T::RParen),
         )(i)
-    }))(i)
+    })(i)
 }
 
-fn parameter(i: Span) -> Res<Param> {
-    let (i, (name, typ)) = separated_pair(spaced(identifier), tag(":"), spaced(type_reference))(i)?;
+fn parameter(i: Tokens) -> Res<Param> {
+    let start = i;
+    let (i, (name, typ)) = separated_pair(identifier, tok(T::Colon), type_reference)(i)?;
 
-    let p = Param { name, typ };
+    let p = Param {
+        loc: start.span_to(i),
+        name,
+        typ,
+    };
     Ok((i, p))
 }
 
-fn type_reference(i: Span) -> Res<TypeRef> {
-    let (i, id) = spaced(identifier)(i)?;
+fn type_reference(i: Tokens) -> Res<TypeRef> {
+    let (i, id) = identifier(i)?;
     Ok((i, TypeRef { id }))
 }
 
-fn block(i: Span) -> Res<Block> {
-    let p = spaced(delimited(stag("{"), many0(statement), cut(stag("}"))));
-    map(p, |items| Block { items })(i)
+/// Statements that fail to parse are reported and replaced with
+/// `Statement::Error`, then parsing picks up after them.
+fn block(i: Tokens) -> Res<Block> {
+    let start = i;
+    let (mut i, _) = tok(T::LBrace)(i)?;
+    let mut items = Vec::new();
+    let mut tail = None;
+
+    loop {
+        match i.first().kind {
+            // items don't nest, so reaching one means the block was
+            // never closed
+            T::RBrace | T::Eof | T::Fn | T::Pub => {
+                let (i, _) = cut(tok(T::RBrace))(i)?;
+                let loc = start.span_to(i);
+                return Ok((i, Block { loc, items, tail }));
+            }
+            _ => {}
+        }
+
+        match block_item(i) {
+            Ok((i2, BlockItem::Statement(stat))) => {
+                items.push(stat);
+                i = i2;
+            }
+            Ok((i2, BlockItem::Tail(ex))) => {
+                tail = Some(Box::new(ex));
+                i = i2;
+            }
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                i.report(e);
+                let (i2, loc) = skip_statement(i);
+                items.push(Statement::Error(loc));
+                i = i2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+enum BlockItem {
+    Statement(Statement),
+    /// An expression right before the `}` closing the block
+    Tail(Expr),
 }
 
-fn statement(i: Span) -> Res<Statement> {
+fn block_item(i: Tokens) -> Res<BlockItem> {
+    expecting(
+        "statement",
+        alt((
+            map(non_expression_statement, BlockItem::Statement),
+            expression_item,
+        )),
+    )(i)
+}
+
+/// An expression statement, or the block's tail if it's followed by the
+/// `}` instead of a `;`. Either way the expression is only parsed once.
+fn expression_item(i: Tokens) -> Res<BlockItem> {
+    let (i, ex) = expression(i)?;
+    if i.first().kind == T::RBrace {
+        return Ok((i, BlockItem::Tail(ex)));
+    }
+
+    let (i, _) = semicolon("`;` after expression")(i)?;
+    Ok((i, BlockItem::Statement(Statement::Expr(ex))))
+}
+
+fn statement(i: Tokens) -> Res<Statement> {
+    expecting(
+        "statement",
+        alt((
+            non_expression_statement,
+            map(
+                terminated(expression, semicolon("`;` after expression")),
+                Statement::Expr,
+            ),
+        )),
+    )(i)
+}
+
+/// Any statement but an expression one. A block at the start of a
+/// statement is one on its own, never the start of an expression.
+fn non_expression_statement(i: Tokens) -> Res<Statement> {
     alt((
         map(block, Statement::Block),
         map(loop_st, Statement::Loop),
         map(if_st, Statement::If),
-        terminated(
-            spaced(alt((
-                map(stag("break"), |_| Statement::Break),
-                map(stag("continue"), |_| Statement::Continue),
-                map(return_st, Statement::Return),
-                map(var_decl, Statement::VDecl),
-                map(expression, Statement::Expr),
-            ))),
-            cut(stag(";")),
+        map(
+            terminated(tok(T::Break), semicolon("`;` after `break`")),
+            |t| Statement::Break(t.span),
+        ),
+        map(
+            terminated(tok(T::Continue), semicolon("`;` after `continue`")),
+            |t| Statement::Continue(t.span),
         ),
-    ))(i)
-}
-
-fn if_st(i: Span) -> Res<If> {
-    let (i, _) = stag("if")(i)?;
-    spaced(context(
-        "if",
         map(
-            tuple((spaced(expression), spaced(block))),
-            |(cond, body)| If { cond, body },
+            terminated(return_st, semicolon("`;` after return statement")),
+            Statement::Return,
+        ),
+        map(
+            terminated(var_decl, semicolon("`;` after let binding")),
+            Statement::VDecl,
         ),
     ))(i)
 }
 
-fn loop_st(i: Span) -> Res<Loop> {
-    let (i, _) = stag("loop")(i)?;
-    spaced(context("loop", map(block, |body| Loop { body })))(i)
+fn semicolon<'a>(what: &'static str) -> impl Fn(Tokens<'a>) -> Res<'a, Token> {
+    cut(expect(T::Semicolon, what))
 }
 
-fn return_st(i: Span) -> Res<Return> {
-    let (i, _) = stag("return")(i)?;
-    spaced(context("return statement", |i| {
+fn if_st(i: Tokens) -> Res<If> {
+    let start = i;
+    let (i, _) = tok(T::If)(i)?;
+    let (i, (cond, body)) = context("if statement", tuple((expression, block)))(i)?;
+
+    let loc = start.span_to(i);
+    Ok((i, If { loc, cond, body }))
+}
+
+fn loop_st(i: Tokens) -> Res<Loop> {
+    let start = i;
+    let (i, _) = tok(T::Loop)(i)?;
+    let (i, body) = context("loop", block)(i)?;
+
+    let loc = start.span_to(i);
+    Ok((i, Loop { loc, body }))
+}
+
+fn return_st(i: Tokens) -> Res<Return> {
+    let start = i;
+    let (i, _) = tok(T::Return)(i)?;
+    context("return statement", move |i| {
         let (i, expr) = opt(expression)(i)?;
-        let ret = Return { expr };
+        let ret = Return {
+            loc: start.span_to(i),
+            expr,
+        };
         Ok((i, ret))
-    }))(i)
+    })(i)
 }
 
-fn var_decl(i: Span) -> Res<VDecl> {
-    spaced(context("let binding", |i| {
-        let (i, _) = tag("let")(i)?;
-        cut(|i| {
-            let (i, name) = spaced(identifier)(i)?;
-            let (i, typ) = opt(preceded(stag(":"), spaced(type_reference)))(i)?;
-            let (i, value) = opt(preceded(stag("="), spaced(expression)))(i)?;
-            let vd = VDecl { name, typ, value };
+fn var_decl(i: Tokens) -> Res<VDecl> {
+    let start = i;
+    context("let binding", move |i| {
+        let (i, _) = tok(T::Let)(i)?;
+        cut(move |i| {
+            let (i, name) = identifier(i)?;
+            let (i, typ) = opt(preceded(tok(T::Colon), type_reference))(i)?;
+            let (i, value) = opt(preceded(tok(T::Eq), cut(expression)))(i)?;
+            let vd = VDecl {
+                loc: start.span_to(i),
+                name,
+                typ,
+                value,
+            };
             Ok((i, vd))
         })(i)
-    }))(i)
+    })(i)
+}
+
+fn expression(i: Tokens) -> Res<Expr> {
+    expecting("expression", binary_expression(0))(i)
+}
+
+/// Precedence climbing: parses operands and every binary operator that
+/// binds at least as tightly as `min_bp`
+fn binary_expression(min_bp: u8) -> impl Fn(Tokens) -> Res<Expr> {
+    move |i| {
+        let (mut i, mut lhs) = prefix_expression(i)?;
+
+        while let Ok((i2, operator)) = binary_operator_ex(i) {
+            let (l_bp, r_bp) = binding_power(&operator);
+            if l_bp < min_bp {
+                break;
+            }
+
+            // an operator commits to an operand: backtracking past it
+            // would only blame whatever comes after the whole expression
+            let (i2, rhs) = cut(expecting("expression", binary_expression(r_bp)))(i2)?;
+            lhs = Expr::Bexp(operator.as_expr(Box::new(lhs), Box::new(rhs)));
+            i = i2;
+        }
+
+        Ok((i, lhs))
+    }
+}
+
+/// How tightly an operator binds to its left and right operands. A
+/// higher right side makes it left-associative, and vice versa.
+fn binding_power(operator: &BopEx) -> (u8, u8) {
+    match operator {
+        BopEx::Ass(_) | BopEx::Base(Bop::Assign) => (2, 1),
+        BopEx::Base(Bop::Eq) | BopEx::Base(Bop::Ne) => (3, 4),
+        BopEx::Base(Bop::Gt) | BopEx::Base(Bop::GtEq) => (3, 4),
+        BopEx::Base(Bop::Lt) | BopEx::Base(Bop::LtEq) => (3, 4),
+        BopEx::Base(Bop::Plus) | BopEx::Base(Bop::Minus) => (5, 6),
+        BopEx::Base(Bop::Mul) | BopEx::Base(Bop::Div) => (7, 8),
+    }
 }
 
-fn expression(i: Span) -> Res<Expr> {
+/// Unary operators bind tighter than any binary operator
+fn prefix_expression(i: Tokens) -> Res<Expr> {
+    alt((
+        |i| {
+            let start = i;
+            let (i, operator) = unary_operator(i)?;
+            let (i, operand) = cut(expecting("expression", prefix_expression))(i)?;
+
+            let u = Uexp {
+                loc: start.span_to(i),
+                operator,
+                operand: Box::new(operand),
+            };
+            Ok((i, Expr::Uexp(u)))
+        },
+        postfix_expression,
+    ))(i)
+}
+
+fn postfix_expression(i: Tokens) -> Res<Expr> {
     let (mut i, mut expr) = inner_expression(i)?;
 
     loop {
-        match postfix_expression(expr.clone(), i.clone()) {
-            Ok((i2, expr2)) => {
+        let res = call(&expr)(i);
+        match res {
+            Ok((i2, c)) => {
                 i = i2;
-                expr = expr2;
-                continue;
+                expr = Expr::Call(c);
             }
-            Err(_) => return Ok((i, expr)),
+            Err(nom::Err::Error(_)) => return Ok((i, expr)),
+            // a broken argument list, not a missing one
+            Err(e) => return Err(e),
         }
     }
 }
 
-fn inner_expression(i: Span) -> Res<Expr> {
-    spaced(alt((
-        delimited(stag("("), spaced(expression), stag(")")),
+fn inner_expression(i: Tokens) -> Res<Expr> {
+    alt((
+        delimited(tok(T::LParen), expression, tok(T::RParen)),
         map(block, Expr::Block),
         map(float_lit, Expr::FloatLit),
         map(int_lit, Expr::IntLit),
         map(identifier, Expr::Identifier),
-    )))(i)
-}
-
-fn postfix_expression(lhs: Expr, i: Span) -> Res<Expr> {
-    alt((
-        map(call(&lhs), Expr::Call),
-        map(binary_expression(&lhs), Expr::Bexp),
     ))(i)
 }
 
-fn call<'a>(target: &'a Expr) -> impl Fn(Span) -> Res<Call> + 'a {
+fn call<'a>(target: &'a Expr) -> impl Fn(Tokens) -> Res<Call> + 'a {
     move |i| {
-        let (i, args) = delimited(stag("("), separated_list(stag(","), expression), stag(")"))(i)?;
+        let (i, (_, args, rparen)) = tuple((
+            tok(T::LParen),
+            separated_list(tok(T::Comma), expression),
+            tok(T::RParen),
+        ))(i)?;
 
         let c = Call {
+            loc: target.loc().to(&rparen.span),
             target: Box::new(target.clone()),
             args,
         };
@@ -180,101 +380,146 @@ fn call<'a>(target: &'a Expr) -> impl Fn(Span) -> Res<Call> + 'a {
     }
 }
 
-fn binary_expression<'a>(lhs: &'a Expr) -> impl Fn(Span) -> Res<Bexp> + 'a {
-    move |i| {
-        let (i, operator) = spaced(binary_operator_ex)(i)?;
-        let (i, rhs) = spaced(expression)(i)?;
-
-        Ok((i, operator.as_expr(Box::new(lhs.clone()), Box::new(rhs))))
-    }
+fn unary_operator(i: Tokens) -> Res<Uop> {
+    alt((
+        map(tok(T::Minus), |_| Uop::Neg),
+        map(tok(T::Bang), |_| Uop::Not),
+    ))(i)
 }
 
-fn binary_operator_ex(i: Span) -> Res<BopEx> {
+fn binary_operator_ex(i: Tokens) -> Res<BopEx> {
     alt((
-        map(tag("+="), |_| BopEx::Ass(AssOp::Plus)),
-        map(tag("-="), |_| BopEx::Ass(AssOp::Minus)),
-        map(tag("*="), |_| BopEx::Ass(AssOp::Mul)),
-        map(tag("/="), |_| BopEx::Ass(AssOp::Div)),
-        map(tag("+"), |_| BopEx::Base(Bop::Plus)),
-        map(tag("-"), |_| BopEx::Base(Bop::Minus)),
-        map(tag("*"), |_| BopEx::Base(Bop::Mul)),
-        map(tag("/"), |_| BopEx::Base(Bop::Div)),
-        map(tag("="), |_| BopEx::Base(Bop::Assign)),
-        map(tag(">="), |_| BopEx::Base(Bop::GtEq)),
-        map(tag(">"), |_| BopEx::Base(Bop::Gt)),
-        map(tag("<="), |_| BopEx::Base(Bop::LtEq)),
-        map(tag("<"), |_| BopEx::Base(Bop::Lt)),
+        map(tok(T::PlusEq), |_| BopEx::Ass(AssOp::Plus)),
+        map(tok(T::MinusEq), |_| BopEx::Ass(AssOp::Minus)),
+        map(tok(T::StarEq), |_| BopEx::Ass(AssOp::Mul)),
+        map(tok(T::SlashEq), |_| BopEx::Ass(AssOp::Div)),
+        map(tok(T::Plus), |_| BopEx::Base(Bop::Plus)),
+        map(tok(T::Minus), |_| BopEx::Base(Bop::Minus)),
+        map(tok(T::Star), |_| BopEx::Base(Bop::Mul)),
+        map(tok(T::Slash), |_| BopEx::Base(Bop::Div)),
+        map(tok(T::EqEq), |_| BopEx::Base(Bop::Eq)),
+        map(tok(T::BangEq), |_| BopEx::Base(Bop::Ne)),
+        map(tok(T::Eq), |_| BopEx::Base(Bop::Assign)),
+        map(tok(T::GtEq), |_| BopEx::Base(Bop::GtEq)),
+        map(tok(T::Gt), |_| BopEx::Base(Bop::Gt)),
+        map(tok(T::LtEq), |_| BopEx::Base(Bop::LtEq)),
+        map(tok(T::Lt), |_| BopEx::Base(Bop::Lt)),
     ))(i)
 }
 
-fn float_lit(i: Span) -> Res<FloatLit> {
-    let (i, slice) = alt((
-        recognize(tuple((tag("."), digits))),
-        recognize(tuple((digits, tag("."), opt(digits)))),
-    ))(i)?;
+fn float_lit(i: Tokens) -> Res<FloatLit> {
+    let source = i.source();
+    map_res(tok(T::FloatLit), move |t: Token| {
+        source
+            .text(t.span)
+            .parse::<f64>()
+            .map(|value| FloatLit { loc: t.span, value })
+    })(i)
+}
 
-    let loc = slice.clone();
-    let (_, value) = all_consuming(nom::number::complete::double)(slice)?;
-    let fl = FloatLit { loc, value };
-    Ok((i, fl))
+fn int_lit(i: Tokens) -> Res<IntLit> {
+    let (i2, t) = tok(T::IntLit)(i)?;
+    match i.source().text(t.span).parse::<i64>() {
+        Ok(value) => Ok((i2, IntLit { loc: t.span, value })),
+        Err(_) => Err(nom::Err::Failure(SyntaxError::expected(
+            i,
+            "an integer that fits in 64 bits",
+        ))),
+    }
 }
 
-fn int_lit(i: Span) -> Res<IntLit> {
-    map_res(digits, move |span: Span| {
-        match span.slice().parse::<i64>() {
-            Ok(value) => {
-                let il = IntLit { loc: span, value };
-                Ok(il)
-            }
-            Err(_) => Err(nom::Err::Error(nom::error::ErrorKind::Tag)),
-        }
+fn identifier(i: Tokens) -> Res<Id> {
+    let source = i.source();
+    map(tok(T::Identifier), move |t: Token| {
+        Id::new(t.span, source.text(t.span))
     })(i)
 }
 
-fn digits(i: Span) -> Res<Span> {
-    let int_chars = "0123456789";
-    take_while1(move |c| int_chars.contains(c))(i)
-}
+/// Skips past a statement that failed to parse: through its `;`, or
+/// through the `}` closing a block it opened. Stops early at the end of
+/// the enclosing block or the start of an item.
+fn skip_statement(i: Tokens) -> (Tokens, Span) {
+    let start = i;
+    let mut i = i;
+    let mut depth = 0;
 
-static VALID_ID_CHARS: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_";
+    loop {
+        let kind = i.first().kind;
+        match kind {
+            T::Eof | T::Fn | T::Pub => break,
+            T::RBrace if depth == 0 => break,
+            _ => {}
+        }
 
-fn identifier(i: Span) -> Res<Id> {
-    let (i, span) = take_while1(|c| VALID_ID_CHARS.contains(c))(i)?;
+        i = i.advance();
+        match kind {
+            T::Semicolon if depth == 0 => break,
+            T::LBrace => depth += 1,
+            T::RBrace => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
 
-    let id = Id::new(span);
-    Ok((i, id))
+    (i, start.span_to(i))
 }
 
-/// f, but skip whitespace before and after (including newlines, and comments)
-fn spaced<O, E: ParseError<Span>, F>(f: F) -> impl Fn(Span) -> IResult<Span, O, E>
-where
-    F: Fn(Span) -> IResult<Span, O, E>,
-{
-    preceded(
-        many0(alt((
-            tag(" "),
-            tag("\t"),
-            tag("\r"),
-            tag("\n"),
-            preceded(tag("//"), take_until("\n")),
-        ))),
-        f,
-    )
+/// Skips past an item that failed to parse, up to the next `fn` or
+/// `pub fn`
+fn skip_item(i: Tokens) -> (Tokens, Span) {
+    let start = i;
+    let mut i = i.advance();
+    if start.first().kind == T::Pub && i.first().kind == T::Fn {
+        i = i.advance();
+    }
+
+    while !matches!(i.first().kind, T::Eof | T::Fn | T::Pub) {
+        i = i.advance();
+    }
+
+    (i, start.span_to(i))
 }
 
-fn stag(s: &'static str) -> impl Fn(Span) -> Res<Span> {
-    spaced(tag(s))
+/// A single token of the given kind
+fn tok(kind: T) -> impl Fn(Tokens) -> Res<Token> {
+    expect(kind, kind.describe())
 }
 
-/// Whitespace excluding newlines
-fn linesp(i: Span) -> Res<Span> {
-    let chars = " \t";
+/// A single token of the given kind, described as `what` in errors
+fn expect(kind: T, what: &'static str) -> impl Fn(Tokens) -> Res<Token> {
+    move |i: Tokens| {
+        let t = i.first();
+        if t.kind == kind {
+            Ok((i.advance(), *t))
+        } else {
+            Err(nom::Err::Error(SyntaxError::expected(i, what)))
+        }
+    }
+}
 
-    take_while(move |c| chars.contains(c))(i)
+/// Runs `f`, and if it fails without getting past the first token,
+/// reports `what` as expected instead of every token it tried
+fn expecting<'a, O, F>(what: &'static str, f: F) -> impl Fn(Tokens<'a>) -> Res<'a, O>
+where
+    F: Fn(Tokens<'a>) -> Res<'a, O>,
+{
+    move |i| match f(i) {
+        Err(nom::Err::Error(e)) if e.at == i => Err(nom::Err::Error(SyntaxError {
+            expected: vec![what],
+            ..e
+        })),
+        res => res,
+    }
 }
 
-// Consumes nothing, returns a span for location information
-fn loc(i: Span) -> Res<Span> {
-    let o = i.take(0);
-    Ok((i, o))
+/// Succeeds at the end of input, without consuming anything
+fn eof(i: Tokens) -> Res<()> {
+    match i.first().kind {
+        T::Eof => Ok((i, ())),
+        _ => Err(nom::Err::Error(SyntaxError::expected(i, T::Eof.describe()))),
+    }
 }
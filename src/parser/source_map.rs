@@ -0,0 +1,145 @@
+use super::{Position, Span};
+use std::convert::TryFrom;
+use std::io;
+use std::path::Path;
+
+/// Identifies a file loaded into the `SourceMap`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId(u32);
+
+/// A `.mor` source file
+pub struct Source {
+    pub id: FileId,
+    /// Global position of the first byte, see `SourceMap`
+    pub base: u32,
+    pub input: String,
+    name: String,
+    /// Byte offset of the start of each line
+    line_starts: Vec<usize>,
+}
+
+impl Source {
+    /// Span of `len` bytes starting at byte `offset` of this file
+    pub fn span(&self, offset: usize, len: usize) -> Span {
+        let lo = self.base + offset as u32;
+        Span {
+            file: self.id,
+            lo,
+            hi: lo + len as u32,
+        }
+    }
+
+    /// Byte offset of the start of `span`, which must be in this file
+    pub fn offset(&self, span: Span) -> usize {
+        debug_assert_eq!(span.file, self.id);
+        (span.lo - self.base) as usize
+    }
+
+    pub fn text(&self, span: Span) -> &str {
+        let offset = self.offset(span);
+        &self.input[offset..offset + span.len()]
+    }
+
+    pub fn position(&self, span: Span) -> Position<'_> {
+        let offset = self.offset(span);
+        let (line, line_start) = self.line_of(offset);
+        let column = self.input[line_start..offset].chars().count();
+
+        Position {
+            span,
+            line,
+            column,
+            source: self,
+        }
+    }
+
+    /// Zero-based line containing byte `offset`, and where it starts
+    pub fn line_of(&self, offset: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        (line, self.line_starts[line])
+    }
+
+    /// Text of a zero-based line, without its `\n` or `\r\n`
+    pub fn line(&self, line: usize) -> &str {
+        let start = self.line_starts[line];
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or(self.input.len());
+        let text = &self.input[start..end];
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        text.strip_suffix('\r').unwrap_or(text)
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_ref()
+    }
+}
+
+/// Every source file loaded by one compilation (or REPL session), which
+/// is passed along to whatever needs to look at source text.
+///
+/// Each file gets an id and its own range of global positions, which is
+/// what `Span`s are made of. Ranges are one byte longer than the file so
+/// an end-of-file span never lands in the next one.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<Source>,
+    next_base: u32,
+}
+
+impl SourceMap {
+    pub fn load_path<P: AsRef<Path>>(&mut self, path: P) -> io::Result<FileId> {
+        let path = path.as_ref();
+        let input = std::fs::read_to_string(path)?;
+        self.load(path.to_string_lossy().into(), input)
+    }
+
+    #[cfg(test)]
+    pub fn load_string<S>(&mut self, input: S) -> io::Result<FileId>
+    where
+        S: Into<String>,
+    {
+        self.load("<memory>".into(), input.into())
+    }
+
+    pub fn load(&mut self, name: String, input: String) -> io::Result<FileId> {
+        let too_big = || io::Error::other(format!("{}: source map is full", name));
+        let id = FileId(u32::try_from(self.files.len()).map_err(|_| too_big())?);
+        let base = self.next_base;
+        self.next_base = u32::try_from(input.len())
+            .ok()
+            .and_then(|len| base.checked_add(len))
+            .and_then(|end| end.checked_add(1))
+            .ok_or_else(too_big)?;
+
+        let line_starts = std::iter::once(0)
+            .chain(input.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        self.files.push(Source {
+            id,
+            base,
+            input,
+            name,
+            line_starts,
+        });
+        Ok(id)
+    }
+
+    pub fn get(&self, file: FileId) -> &Source {
+        &self.files[file.0 as usize]
+    }
+
+    /// Forgets `file` and every file loaded after it, so their ids and
+    /// positions get reused. Spans into them must not be used anymore.
+    pub fn unload_from(&mut self, file: FileId) {
+        if let Some(source) = self.files.get(file.0 as usize) {
+            self.next_base = source.base;
+            self.files.truncate(file.0 as usize);
+        }
+    }
+}
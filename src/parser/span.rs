@@ -1,279 +1,48 @@
-use super::Source;
-use nom::{
-    AsBytes, Compare, CompareResult, FindSubstring, InputIter, InputLength, InputTake, Offset,
-    Slice, UnspecializedInput,
-};
+use super::{FileId, Position, Source, SourceMap};
 use std::fmt;
-use std::ops::{RangeFrom, RangeTo};
-use std::rc::Rc;
 
-use super::Position;
-
-#[derive(Clone)]
+/// A range of bytes in one file of a `SourceMap`. `lo` and `hi` are
+/// global positions, so spans from different files never compare equal.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Span {
-    pub source: Rc<Source>,
-    pub offset: usize,
-    pub len: usize,
+    pub file: FileId,
+    pub lo: u32,
+    pub hi: u32,
 }
 
 impl fmt::Debug for Span {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}(at {}, len={}, {:#?}..)",
-            self.source.name(),
-            self.offset,
-            self.len,
-            self.slice()
-        )
+        write!(f, "{}..{}", self.lo, self.hi)
     }
 }
 
 impl Span {
-    pub fn chars(&self) -> SpanIterElem {
-        SpanIterElem {
-            span: self.clone(),
-            offset: 0,
-        }
-    }
-
-    pub fn char_indices(&self) -> SpanIter {
-        SpanIter {
-            span: self.clone(),
-            offset: 0,
-        }
+    pub fn source<'m>(&self, map: &'m SourceMap) -> &'m Source {
+        map.get(self.file)
     }
 
     pub fn len(&self) -> usize {
-        self.len
-    }
-
-    pub fn slice(&self) -> &str {
-        &self.source.input[self.offset..self.offset + self.len]
-    }
-
-    pub fn position(&self) -> Position {
-        let mut offset = self.offset;
-        let mut line = 0;
-        let mut column = 0;
-
-        for (j, l) in self.source.lines.iter().enumerate() {
-            if offset <= l.len() {
-                line = j;
-                column = offset;
-                break;
-            } else {
-                // 1 accounts for the '\n'
-                offset = offset - l.len() - 1;
-            }
-        }
-
-        Position {
-            span: self.clone(),
-            line,
-            column,
-        }
-    }
-}
-
-impl PartialEq for Span {
-    fn eq(&self, other: &Self) -> bool {
-        Rc::ptr_eq(&self.source, &other.source)
-            && self.offset == other.offset
-            && self.len == other.len
-    }
-}
-impl Eq for Span {}
-
-impl InputLength for Span {
-    fn input_len(&self) -> usize {
-        self.len
-    }
-}
-
-pub struct SpanIterElem {
-    span: Span,
-    offset: usize,
-}
-
-impl Iterator for SpanIterElem {
-    type Item = char;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(c) = self.span.slice()[self.offset..].chars().next() {
-            self.offset += c.len_utf8();
-            Some(c)
-        } else {
-            None
-        }
-    }
-}
-
-pub struct SpanIter {
-    span: Span,
-    offset: usize,
-}
-
-impl Iterator for SpanIter {
-    type Item = (usize, char);
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(c) = self.span.slice()[self.offset..].chars().next() {
-            let i = self.offset;
-            self.offset += c.len_utf8();
-            Some((i, c))
-        } else {
-            None
-        }
-    }
-}
-
-impl InputIter for Span {
-    type Item = char;
-    type Iter = SpanIter;
-    type IterElem = SpanIterElem;
-
-    #[inline]
-    fn iter_indices(&self) -> Self::Iter {
-        self.char_indices()
-    }
-    #[inline]
-    fn iter_elements(&self) -> Self::IterElem {
-        self.chars()
-    }
-    fn position<P>(&self, predicate: P) -> Option<usize>
-    where
-        P: Fn(Self::Item) -> bool,
-    {
-        for (o, c) in self.char_indices() {
-            if predicate(c) {
-                return Some(o);
-            }
-        }
-        None
-    }
-    #[inline]
-    fn slice_index(&self, count: usize) -> Option<usize> {
-        let mut cnt = 0;
-        for (index, _) in self.char_indices() {
-            if cnt == count {
-                return Some(index);
-            }
-            cnt += 1;
-        }
-        if cnt == count {
-            return Some(self.len());
-        }
-        None
-    }
-}
-
-impl InputTake for Span {
-    fn take(&self, count: usize) -> Self {
-        Self {
-            source: self.source.clone(),
-            offset: self.offset,
-            len: count,
-        }
-    }
-    fn take_split(&self, count: usize) -> (Self, Self) {
-        (
-            Self {
-                source: self.source.clone(),
-                offset: self.offset + count,
-                len: self.len - count,
-            },
-            Self {
-                source: self.source.clone(),
-                offset: self.offset,
-                len: count,
-            },
-        )
-    }
-}
-
-impl UnspecializedInput for Span {}
-
-impl Compare<&str> for Span {
-    #[inline(always)]
-    fn compare(&self, t: &str) -> CompareResult {
-        let pos = self.chars().zip(t.chars()).position(|(a, b)| a != b);
-
-        match pos {
-            Some(_) => CompareResult::Error,
-            None => {
-                if self.len() >= t.len() {
-                    CompareResult::Ok
-                } else {
-                    CompareResult::Incomplete
-                }
-            }
-        }
+        (self.hi - self.lo) as usize
     }
 
-    //FIXME: this version is too simple and does not use the current locale
-    #[inline(always)]
-    fn compare_no_case(&self, t: &str) -> CompareResult {
-        let pos = self
-            .chars()
-            .zip(t.chars())
-            .position(|(a, b)| a.to_lowercase().zip(b.to_lowercase()).any(|(a, b)| a != b));
-
-        let res = match pos {
-            Some(_) => CompareResult::Error,
-            None => {
-                if self.len() >= t.len() {
-                    CompareResult::Ok
-                } else {
-                    CompareResult::Incomplete
-                }
-            }
-        };
-        println!("{:#?} compare_no_case with {:#?} => {:#?}", self, t, res);
-        res
+    pub fn is_empty(&self) -> bool {
+        self.lo == self.hi
     }
-}
 
-impl Slice<RangeFrom<usize>> for Span {
-    fn slice(&self, range: RangeFrom<usize>) -> Self {
-        Self {
-            source: self.source.clone(),
-            offset: self.offset + range.start,
-            len: self.len - range.start,
+    /// From the start of `self` to the end of `end`
+    pub fn to(&self, end: &Span) -> Span {
+        Span {
+            file: self.file,
+            lo: self.lo,
+            hi: end.hi,
         }
     }
-}
 
-impl Slice<RangeTo<usize>> for Span {
-    fn slice(&self, range: RangeTo<usize>) -> Self {
-        Self {
-            source: self.source.clone(),
-            offset: self.offset,
-            len: range.end,
-        }
+    pub fn text<'m>(&self, map: &'m SourceMap) -> &'m str {
+        self.source(map).text(*self)
     }
-}
-
-impl Offset for Span {
-    fn offset(&self, second: &Self) -> usize {
-        second.offset - self.offset
-    }
-}
-
-impl AsBytes for Span {
-    fn as_bytes(&self) -> &[u8] {
-        return self.slice().as_bytes();
-    }
-}
-
-impl Into<String> for Span {
-    fn into(self) -> String {
-        self.slice().into()
-    }
-}
 
-impl FindSubstring<&str> for Span {
-    fn find_substring(&self, substr: &str) -> Option<usize> {
-        self.slice().find(substr)
+    pub fn position<'m>(&self, map: &'m SourceMap) -> Position<'m> {
+        self.source(map).position(*self)
     }
 }
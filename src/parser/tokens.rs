@@ -0,0 +1,113 @@
+use super::{Source, Span, SyntaxError};
+use crate::lexer::{Token, TokenKind};
+use nom::{InputLength, Slice};
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::RangeTo;
+
+/// Parser input: what's left of the token stream. Never empty, the
+/// trailing `TokenKind::Eof` is not consumed, so there's always a token
+/// to point diagnostics at.
+///
+/// Also carries the file the tokens come from, and the errors recovered
+/// from so far, shared by every copy.
+#[derive(Clone, Copy)]
+pub struct Tokens<'a> {
+    toks: &'a [Token],
+    source: &'a Source,
+    errors: &'a RefCell<Vec<SyntaxError<Span>>>,
+}
+
+impl<'a> Tokens<'a> {
+    pub fn new(
+        toks: &'a [Token],
+        source: &'a Source,
+        errors: &'a RefCell<Vec<SyntaxError<Span>>>,
+    ) -> Self {
+        assert!(
+            toks.last().map(|t| t.kind) == Some(TokenKind::Eof),
+            "token streams end with Eof"
+        );
+        Self {
+            toks,
+            source,
+            errors,
+        }
+    }
+
+    pub fn source(&self) -> &'a Source {
+        self.source
+    }
+
+    pub fn first(&self) -> &'a Token {
+        &self.toks[0]
+    }
+
+    /// Everything but the first token (Eof stays put)
+    pub fn advance(&self) -> Self {
+        match self.first().kind {
+            TokenKind::Eof => *self,
+            _ => Self {
+                toks: &self.toks[1..],
+                ..*self
+            },
+        }
+    }
+
+    /// Where the next token starts
+    pub fn span(&self) -> Span {
+        self.first().span
+    }
+
+    /// Span covering the tokens from here up to (not including) `end`
+    pub fn span_to(&self, end: Tokens<'a>) -> Span {
+        let first = self.first().span;
+        let hi = match self.toks.len() - end.toks.len() {
+            0 => first.lo,
+            n => self.toks[n - 1].span.hi,
+        };
+
+        Span { hi, ..first }
+    }
+
+    /// Records an error to report once parsing is done. Backtracking can
+    /// run into the same error twice, only the first one is kept.
+    pub fn report(&self, e: SyntaxError<Tokens>) {
+        let e = SyntaxError {
+            at: e.at.span(),
+            expected: e.expected,
+            context: e.context,
+        };
+
+        let mut errors = self.errors.borrow_mut();
+        if !errors.iter().any(|prev| prev.at == e.at) {
+            errors.push(e);
+        }
+    }
+}
+
+impl<'a> fmt::Debug for Tokens<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}..", self.first())
+    }
+}
+
+impl<'a> PartialEq for Tokens<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.toks, other.toks)
+    }
+}
+
+impl<'a> InputLength for Tokens<'a> {
+    fn input_len(&self) -> usize {
+        self.toks.len()
+    }
+}
+
+/// Only required by the bounds of `nom::combinator::cut`, which never
+/// actually slices its input
+impl<'a> Slice<RangeTo<usize>> for Tokens<'a> {
+    fn slice(&self, _range: RangeTo<usize>) -> Self {
+        unreachable!("token streams are only ever advanced")
+    }
+}
@@ -0,0 +1,229 @@
+use crate::{ast, ir, middle, parser, target};
+use parser::{Error, FileId, SourceMap, Span};
+use std::io::{self, BufRead, Write};
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Name of the function REPL statements are collected into
+const REPL_FN: &str = "__repl";
+
+/// Everything entered so far. There is no persistent machine state:
+/// statements are replayed, in order, every time an expression is
+/// evaluated.
+#[derive(Default)]
+pub struct Session {
+    /// Each line is a file, kept for as long as its items are
+    sources: SourceMap,
+    funs: Vec<ast::FDecl>,
+    stats: Vec<ast::Statement>,
+}
+
+impl Session {
+    /// Parses and evaluates one line, returning the value of a bare
+    /// expression. Lines that fail leave the session untouched.
+    pub fn eval(&mut self, line: &str) -> Result<Option<i64>> {
+        let file = self.sources.load("<repl>".into(), line.into())?;
+        let result = self.eval_file(file);
+        // only declarations and statements are kept, and still point into it
+        if !matches!(result, Ok(None)) {
+            self.sources.unload_from(file);
+        }
+        result
+    }
+
+    fn eval_file(&mut self, file: FileId) -> Result<Option<i64>> {
+        let source = self.sources.get(file);
+        let here = source.span(0, 0);
+
+        match parser::parse_repl_line(source)? {
+            ast::ReplLine::FDecl(fun) => {
+                let name = fun.name.value.clone();
+                let previous = match self.funs.iter().position(|f| f.name.value == name) {
+                    Some(index) => Some(std::mem::replace(&mut self.funs[index], fun)),
+                    None => {
+                        self.funs.push(fun);
+                        None
+                    }
+                };
+                if let Err(e) = self.exec(here, None) {
+                    match previous {
+                        Some(previous) => {
+                            if let Some(f) = self.funs.iter_mut().find(|f| f.name.value == name) {
+                                *f = previous;
+                            }
+                        }
+                        None => {
+                            self.funs.pop();
+                        }
+                    }
+                    return Err(e);
+                }
+                Ok(None)
+            }
+            ast::ReplLine::Statement(stat) => {
+                self.stats.push(stat);
+                if let Err(e) = self.exec(here, None) {
+                    self.stats.pop();
+                    return Err(e);
+                }
+                Ok(None)
+            }
+            ast::ReplLine::Expr(expr) => {
+                let ret = ast::Statement::Return(ast::Return {
+                    loc: *expr.loc(),
+                    expr: Some(expr),
+                });
+                self.exec(here, Some(ret)).map(Some)
+            }
+        }
+    }
+
+    /// Lowers every declaration plus the statements so far (and `last`,
+    /// if any) and runs them through the interpreter
+    fn exec(&self, here: Span, last: Option<ast::Statement>) -> Result<i64> {
+        // only returns a value when evaluating an expression
+        let ret = last.as_ref().map(|_| ast::TypeRef {
+            id: ast::Id {
+                loc: here,
+                value: "i64".into(),
+            },
+        });
+        let mut items = self.stats.clone();
+        items.extend(last);
+
+        let mut funs = self.funs.clone();
+        funs.push(ast::FDecl {
+            loc: here,
+            name: ast::Id {
+                loc: here,
+                value: REPL_FN.into(),
+            },
+            params: Vec::new(),
+            ret,
+            body: ast::Block {
+                loc: here,
+                items,
+                tail: None,
+            },
+            public: false,
+        });
+
+        let target = target::host();
+        let funcs = middle::transform(&ast::Unit { funs }, &self.sources, target)?;
+        let v: Vec<&ir::Func> = funcs.iter().collect();
+        let entry = middle::mangle::mangle(&[REPL_FN]);
+        // runtime errors name functions by symbol, but nobody typed those,
+        // and the function wrapping the lines isn't worth naming at all
+        let result = ir::interp::Interp::new(&v[..], target)
+            .call(&entry, &[])
+            .map_err(|e| {
+                let message = middle::mangle::demangle_text(&e.to_string());
+                let prefix = format!("{}: ", REPL_FN);
+                match message.strip_prefix(&prefix) {
+                    Some(message) => io::Error::other(message.to_string()),
+                    None => io::Error::other(message),
+                }
+            })?;
+        Ok(result)
+    }
+}
+
+/// Reads lines from stdin until it's closed, printing the value of each
+/// expression and any diagnostics
+pub fn run() -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut session = Session::default();
+
+    loop {
+        let mut input = String::new();
+        // keep reading while a `{` is left open
+        loop {
+            print!("{}", if input.is_empty() { "> " } else { "| " });
+            io::stdout().flush()?;
+
+            match lines.next() {
+                Some(line) => {
+                    input.push_str(&line?);
+                    input.push('\n');
+                }
+                None => return Ok(()),
+            }
+            if depth(&input) <= 0 {
+                break;
+            }
+        }
+
+        if input.trim().is_empty() {
+            continue;
+        }
+
+        match session.eval(&input) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => {}
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+/// How many `{` are left unclosed
+fn depth(input: &str) -> i64 {
+    input.chars().fold(0, |depth, c| match c {
+        '{' => depth + 1,
+        '}' => depth - 1,
+        _ => depth,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn survives_runaway_recursion() {
+        let mut session = Session::default();
+        session
+            .eval("fn f(n: i64) -> i64 { return f(n + 1); }\n")
+            .unwrap();
+        let err = session.eval("f(1)\n").unwrap_err();
+        assert_eq!(err.to_string(), "f: stack overflow");
+        assert_eq!(session.eval("let x = 2;\n").unwrap(), None);
+        assert_eq!(session.eval("x * 21\n").unwrap(), Some(42));
+    }
+
+    #[test]
+    fn runtime_errors_leave_out_the_wrapper() {
+        let mut session = Session::default();
+        let err = session.eval("1 / 0\n").unwrap_err();
+        assert_eq!(err.to_string(), "division by zero");
+        session
+            .eval("fn div(a: i64, b: i64) -> i64 { return a / b; }\n")
+            .unwrap();
+        let err = session.eval("div(1, 0)\n").unwrap_err();
+        assert_eq!(err.to_string(), "div: division by zero");
+    }
+
+    #[test]
+    fn forgets_lines_it_does_not_keep() {
+        let mut session = Session::default();
+        session.eval("let a = 1;\n").unwrap();
+        for _ in 0..3 {
+            assert_eq!(session.eval("a + 1\n").unwrap(), Some(2));
+            assert!(session.eval("b + 1\n").is_err());
+        }
+
+        // only the first line is still taking up positions
+        let file = session.sources.load_string("").unwrap();
+        assert_eq!(
+            session.sources.get(file).base,
+            "let a = 1;\n".len() as u32 + 1
+        );
+        session.sources.unload_from(file);
+
+        // and what was kept still points at the right text
+        colored::control::set_override(false);
+        let err = session.eval("let c = a + d;\n").unwrap_err();
+        assert!(err.to_string().contains("<repl>:1:13: "), "{}", err);
+        assert_eq!(session.eval("a\n").unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::ir::Reg;
+use std::path::Path;
+use std::process::Command;
+
+/// Everything that differs between the platforms we can build for
+pub trait Target: Sync {
+    /// Name as passed to `--target`
+    fn name(&self) -> &'static str;
+
+    fn object_format(&self) -> ObjectFormat;
+
+    fn calling_convention(&self) -> CallingConvention;
+
+    /// Symbol the OS loader jumps to when starting the process
+    fn entry_symbol(&self) -> &'static str {
+        "_start"
+    }
+
+    fn entry_exit(&self) -> EntryExit;
+
+    /// Extension of executables, without the dot (empty if none)
+    fn exe_extension(&self) -> &'static str;
+
+    /// Command that links `obj` into the executable `out`
+    fn link_command(&self, obj: &Path, out: &Path) -> Command;
+}
+
+/// Object file formats, named after nasm's `-f` flag
+#[derive(Debug, Clone, Copy)]
+pub enum ObjectFormat {
+    Elf64,
+    /// COFF, for x64
+    Win64,
+}
+
+impl ObjectFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Elf64 => "o",
+            Self::Win64 => "obj",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CallingConvention {
+    SysV,
+    Win64,
+}
+
+impl CallingConvention {
+    /// Registers integer arguments are passed in, in order
+    pub fn arg_regs(self) -> &'static [Reg] {
+        match self {
+            Self::SysV => &[Reg::RDI, Reg::RSI, Reg::RDX, Reg::RCX, Reg::R8, Reg::R9],
+            Self::Win64 => &[Reg::RCX, Reg::RDX, Reg::R8, Reg::R9],
+        }
+    }
+
+    pub fn return_reg(self) -> Reg {
+        Reg::RAX
+    }
+
+    /// Bytes the caller reserves right above the return address, for the
+    /// callee to spill its register arguments to
+    pub fn shadow_space(self) -> i64 {
+        match self {
+            Self::SysV => 0,
+            Self::Win64 => 32,
+        }
+    }
+}
+
+/// How the process entry point hands control back to the OS
+#[derive(Debug, Clone, Copy)]
+pub enum EntryExit {
+    /// Plain `ret` - on win64, the loader's thunk exits with `rax`
+    Ret,
+    /// `exit` syscall with the return value as status - there is
+    /// nothing to return to from a Linux `_start`
+    Syscall(i64),
+}
+
+pub struct LinuxX64;
+
+impl Target for LinuxX64 {
+    fn name(&self) -> &'static str {
+        "x86_64-linux"
+    }
+
+    fn object_format(&self) -> ObjectFormat {
+        ObjectFormat::Elf64
+    }
+
+    fn calling_convention(&self) -> CallingConvention {
+        CallingConvention::SysV
+    }
+
+    fn entry_exit(&self) -> EntryExit {
+        EntryExit::Syscall(60)
+    }
+
+    fn exe_extension(&self) -> &'static str {
+        ""
+    }
+
+    fn link_command(&self, obj: &Path, out: &Path) -> Command {
+        // no libc, no crt: `_start` is ours and leaves through `exit`
+        let mut cmd = Command::new("ld");
+        cmd.arg("-e").arg(self.entry_symbol());
+        cmd.arg("-o").arg(out).arg(obj);
+        cmd
+    }
+}
+
+pub struct WindowsX64;
+
+impl Target for WindowsX64 {
+    fn name(&self) -> &'static str {
+        "x86_64-windows"
+    }
+
+    fn object_format(&self) -> ObjectFormat {
+        ObjectFormat::Win64
+    }
+
+    fn calling_convention(&self) -> CallingConvention {
+        CallingConvention::Win64
+    }
+
+    fn entry_exit(&self) -> EntryExit {
+        EntryExit::Ret
+    }
+
+    fn exe_extension(&self) -> &'static str {
+        "exe"
+    }
+
+    fn link_command(&self, obj: &Path, out: &Path) -> Command {
+        // link.exe is found through PATH, as set up by a developer prompt;
+        // cross-linking uses LLVM's drop-in replacement for it
+        let mut cmd = Command::new(if cfg!(windows) {
+            "link.exe"
+        } else {
+            "lld-link"
+        });
+        cmd.arg("/SUBSYSTEM:CONSOLE");
+        cmd.arg(format!("/ENTRY:{}", self.entry_symbol()));
+        cmd.arg(obj);
+        cmd.arg(format!("/OUT:{}", out.display()));
+        cmd
+    }
+}
+
+pub static ALL: &[&(dyn Target + Sync)] = &[&LinuxX64, &WindowsX64];
+
+pub fn by_name(name: &str) -> Option<&'static dyn Target> {
+    ALL.iter()
+        .find(|t| t.name() == name)
+        .map(|t| *t as &dyn Target)
+}
+
+/// The target matching the machine we're running on
+pub fn host() -> &'static dyn Target {
+    if cfg!(windows) {
+        &WindowsX64
+    } else {
+        &LinuxX64
+    }
+}